@@ -1,4 +1,4 @@
-use darling::FromDeriveInput;
+use darling::{FromDeriveInput, FromMeta};
 use proc_macro::TokenStream;
 use quote::quote;
 use syn::parse_macro_input;
@@ -21,9 +21,112 @@ struct Opts {
     max_take_value: Option<usize>,
     max_memo_size: Option<usize>,
     atomic_batch_transfers: Option<bool>,
-    tx_window: Option<i64>,
-    permitted_drift: Option<i64>,
+    tx_window: Option<u64>,
+    job_instruction_budget: Option<u64>,
+    permitted_drift: Option<u64>,
     mutable: Option<bool>,
+    max_approvals_per_token_or_collection: Option<usize>,
+    max_revoke_approvals: Option<usize>,
+    icrc3: Option<bool>,
+    icrc37: Option<bool>,
+    #[darling(multiple)]
+    supported_standard: Vec<Standard>,
+    #[darling(multiple)]
+    collection_metadata: Vec<CollectionMetadata>,
+    #[darling(multiple)]
+    burner: Vec<String>,
+    memory: Option<MemoryOpts>,
+}
+
+/// `MemoryId`s of the `Storage` derive, `base` packs every structure into
+/// consecutive ids from there, single structures can be moved on top of that.
+#[derive(FromMeta, Default)]
+struct MemoryOpts {
+    base: Option<u8>,
+    /// Path to a `thread_local!` `RefCell<MemoryManager<DefaultMemoryImpl>>`
+    /// to share instead of a private one.
+    manager: Option<String>,
+    /// Ids the canister uses itself, only checked for overlaps.
+    #[darling(multiple)]
+    reserved: Vec<u8>,
+    tokens: Option<u8>,
+    assets: Option<u8>,
+    transactions_index: Option<u8>,
+    transactions_data: Option<u8>,
+    token_approvals: Option<u8>,
+    collection_approvals: Option<u8>,
+    dedup: Option<u8>,
+    owner_index: Option<u8>,
+    supply: Option<u8>,
+    last_id: Option<u8>,
+    holders: Option<u8>,
+    quarantine: Option<u8>,
+    jobs: Option<u8>,
+    active_jobs: Option<u8>,
+    definition_supply: Option<u8>,
+    retired_ids: Option<u8>,
+}
+
+impl MemoryOpts {
+    fn layout(&self) -> Vec<(&'static str, u8)> {
+        // ids of earlier releases, 2 held the plain asset hash list
+        let structures = [
+            ("tokens", 1, self.tokens),
+            ("assets", 10, self.assets),
+            ("transactions_index", 3, self.transactions_index),
+            ("transactions_data", 4, self.transactions_data),
+            ("token_approvals", 5, self.token_approvals),
+            ("collection_approvals", 6, self.collection_approvals),
+            ("dedup", 7, self.dedup),
+            ("owner_index", 8, self.owner_index),
+            ("supply", 9, self.supply),
+            ("last_id", 11, self.last_id),
+            ("holders", 12, self.holders),
+            ("quarantine", 13, self.quarantine),
+            ("jobs", 14, self.jobs),
+            ("active_jobs", 15, self.active_jobs),
+            ("definition_supply", 16, self.definition_supply),
+            ("retired_ids", 17, self.retired_ids),
+        ];
+        let layout: Vec<(&'static str, u8)> = structures
+            .iter()
+            .enumerate()
+            .map(|(i, (name, legacy, id))| {
+                let base = self.base.map(|base| {
+                    base.checked_add(i as u8)
+                        .expect("memory base leaves no room for every structure")
+                });
+                (*name, id.or(base).unwrap_or(*legacy))
+            })
+            .collect();
+        for (i, (name, id)) in layout.iter().enumerate() {
+            // MemoryManager keeps 255 as the unallocated bucket marker
+            if *id == u8::MAX {
+                panic!("memory id of {name} must be below 255");
+            }
+            if let Some((other, _)) = layout[..i].iter().find(|(_, other)| other == id) {
+                panic!("{name} and {other} both use memory id {id}");
+            }
+            if self.reserved.contains(id) {
+                panic!("{name} uses reserved memory id {id}");
+            }
+        }
+        layout
+    }
+}
+
+#[derive(FromMeta, Default)]
+struct Standard {
+    name: String,
+    url: String,
+}
+
+#[derive(FromMeta, Default)]
+struct CollectionMetadata {
+    key: String,
+    text: Option<String>,
+    nat: Option<u64>,
+    int: Option<i64>,
 }
 
 #[proc_macro_derive(Icrc7, attributes(icrc7))]
@@ -101,7 +204,15 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
     };
     let tx_window = match opts.tx_window {
         Some(x) => quote! {
-            fn tx_window() -> usize{
+            fn tx_window() -> u64{
+                #x
+            }
+        },
+        None => quote! {},
+    };
+    let job_instruction_budget = match opts.job_instruction_budget {
+        Some(x) => quote! {
+            fn job_instruction_budget() -> u64{
                 #x
             }
         },
@@ -109,12 +220,347 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
     };
     let permitted_drift = match opts.permitted_drift {
         Some(x) => quote! {
-            fn permitted_drift() -> usize{
+            fn permitted_drift() -> u64{
                 #x
             }
         },
         None => quote! {},
     };
+    let max_approvals_per_token_or_collection = match opts.max_approvals_per_token_or_collection {
+        Some(x) => quote! {
+            fn max_approvals_per_token_or_collection() -> usize{
+                #x
+            }
+        },
+        None => quote! {},
+    };
+    let max_revoke_approvals = match opts.max_revoke_approvals {
+        Some(x) => quote! {
+            fn max_revoke_approvals() -> usize{
+                #x
+            }
+        },
+        None => quote! {},
+    };
+    let mut standard_names = vec!["ICRC-7".to_string(), "ICRC-10".to_string()];
+    let mut standard_urls = vec![
+        quote! { uncensored_greats_dao::ICRC7_URL },
+        quote! { uncensored_greats_dao::ICRC10_URL },
+    ];
+    let mut block_types = vec!["7mint", "7burn", "7xfr"];
+    let mut block_type_urls = vec![quote! { uncensored_greats_dao::ICRC7_URL }; 3];
+    let icrc3 = if opts.icrc3.unwrap_or(true) {
+        standard_names.push("ICRC-3".to_string());
+        standard_urls.push(quote! { uncensored_greats_dao::ICRC3_URL });
+        quote! {
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc3_get_blocks(args: Vec<uncensored_greats_dao::icrc_ledger_types::icrc3::blocks::GetBlocksRequest>) -> uncensored_greats_dao::icrc_ledger_types::icrc3::blocks::GetBlocksResult {
+                #ident::get_blocks(args)
+            }
+
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc3_supported_block_types() -> Vec<uncensored_greats_dao::icrc_ledger_types::icrc3::blocks::SupportedBlockType> {
+                #ident::supported_block_types().into_iter().map(|(block_type, url)| uncensored_greats_dao::icrc_ledger_types::icrc3::blocks::SupportedBlockType{
+                    block_type: block_type.to_string(),
+                    url: url.to_string(),
+                }).collect()
+            }
+
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc3_get_tip_certificate() -> Option<uncensored_greats_dao::icrc_ledger_types::icrc3::blocks::ICRC3DataCertificate> {
+                #ident::tip_certificate()
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let icrc37 = if opts.icrc37.unwrap_or(true) {
+        standard_names.push("ICRC-37".to_string());
+        standard_urls.push(quote! { uncensored_greats_dao::ICRC37_URL });
+        for block_type in [
+            "37approve",
+            "37approve_coll",
+            "37revoke",
+            "37revoke_coll",
+            "37xfr",
+        ] {
+            block_types.push(block_type);
+            block_type_urls.push(quote! { uncensored_greats_dao::ICRC37_URL });
+        }
+        quote! {
+            impl uncensored_greats_dao::Icrc37<#token_type> for #ident {
+                #max_approvals_per_token_or_collection
+                #max_revoke_approvals
+            }
+
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc37_metadata() -> Vec<(String, uncensored_greats_dao::Value)> {
+                #ident::approval_metadata()
+            }
+
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc37_max_approvals_per_token_or_collection() -> Option<uncensored_greats_dao::candid::Nat> {
+                Some(#ident::max_approvals_per_token_or_collection().into())
+            }
+
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc37_max_revoke_approvals() -> Option<uncensored_greats_dao::candid::Nat> {
+                Some(#ident::max_revoke_approvals().into())
+            }
+
+            #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+            pub struct ApprovalInfo {
+                pub spender: uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account,
+                pub from_subaccount: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Subaccount>,
+                pub expires_at: Option<u64>,
+                pub memo: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo>,
+                pub created_at_time: Option<u64>,
+            }
+
+            #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+            pub struct ApproveTokenArg {
+                pub token_id: uncensored_greats_dao::candid::Nat,
+                pub approval_info: ApprovalInfo,
+            }
+
+            #[uncensored_greats_dao::ic_cdk::update]
+            pub fn icrc37_approve_tokens(
+                args: Vec<ApproveTokenArg>,
+            ) -> Vec<Option<std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::ApproveTokenError>>> {
+                match #ident::approve_tokens(args.into_iter().map(|arg| (
+                    arg.token_id.0.to_u64().unwrap_or(0),
+                    arg.approval_info.from_subaccount,
+                    arg.approval_info.spender,
+                    arg.approval_info.expires_at,
+                    arg.approval_info.memo,
+                    arg.approval_info.created_at_time
+                )).collect()){
+                    Ok(m) => {
+                        m.into_iter().map(|i| Some(i.map(|ii| ii.into()))).collect()
+                    }
+                    Err(e) => vec![Some(Err(e))],
+                }
+            }
+
+            #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+            pub struct ApproveCollectionArg {
+                pub approval_info: ApprovalInfo,
+            }
+
+            #[uncensored_greats_dao::ic_cdk::update]
+            pub fn icrc37_approve_collection(
+                args: Vec<ApproveCollectionArg>,
+            ) -> Vec<Option<std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::ApproveCollectionError>>> {
+                match #ident::approve_collection(args.into_iter().map(|arg| (
+                    arg.approval_info.from_subaccount,
+                    arg.approval_info.spender,
+                    arg.approval_info.expires_at,
+                    arg.approval_info.memo,
+                    arg.approval_info.created_at_time
+                )).collect()){
+                    Ok(m) => {
+                        m.into_iter().map(|i| Some(i.map(|ii| ii.into()))).collect()
+                    }
+                    Err(e) => vec![Some(Err(e))],
+                }
+            }
+
+            #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+            pub struct RevokeTokenApprovalArg {
+                pub spender: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>,
+                pub from_subaccount: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Subaccount>,
+                pub token_id: uncensored_greats_dao::candid::Nat,
+                pub memo: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo>,
+                pub created_at_time: Option<u64>,
+            }
+
+            #[uncensored_greats_dao::ic_cdk::update]
+            pub fn icrc37_revoke_token_approvals(
+                args: Vec<RevokeTokenApprovalArg>,
+            ) -> Vec<Option<std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::RevokeTokenApprovalError>>> {
+                match #ident::revoke_token_approvals(args.into_iter().map(|arg| (
+                    arg.token_id.0.to_u64().unwrap_or(0),
+                    arg.from_subaccount,
+                    arg.spender,
+                    arg.memo,
+                    arg.created_at_time
+                )).collect()){
+                    Ok(m) => {
+                        m.into_iter().map(|i| Some(i.map(|ii| ii.into()))).collect()
+                    }
+                    Err(e) => vec![Some(Err(e))],
+                }
+            }
+
+            #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+            pub struct RevokeCollectionApprovalArg {
+                pub spender: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>,
+                pub from_subaccount: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Subaccount>,
+                pub memo: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo>,
+                pub created_at_time: Option<u64>,
+            }
+
+            #[uncensored_greats_dao::ic_cdk::update]
+            pub fn icrc37_revoke_collection_approvals(
+                args: Vec<RevokeCollectionApprovalArg>,
+            ) -> Vec<Option<std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::RevokeCollectionApprovalError>>> {
+                match #ident::revoke_collection_approvals(args.into_iter().map(|arg| (
+                    arg.from_subaccount,
+                    arg.spender,
+                    arg.memo,
+                    arg.created_at_time
+                )).collect()){
+                    Ok(m) => {
+                        m.into_iter().map(|i| Some(i.map(|ii| ii.into()))).collect()
+                    }
+                    Err(e) => vec![Some(Err(e))],
+                }
+            }
+
+            #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+            pub struct IsApprovedArg {
+                pub spender: uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account,
+                pub from_subaccount: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Subaccount>,
+                pub token_id: uncensored_greats_dao::candid::Nat,
+            }
+
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+                match #ident::is_approved(args.into_iter().map(|arg| (
+                    arg.spender,
+                    arg.from_subaccount,
+                    arg.token_id.0.to_u64().unwrap_or(0)
+                )).collect()){
+                    Ok(m) => m,
+                    Err(e) => {
+                        uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+                    }
+                }
+            }
+
+            #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+            pub struct TokenApproval {
+                pub token_id: uncensored_greats_dao::candid::Nat,
+                pub approval_info: ApprovalInfo,
+            }
+
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc37_get_token_approvals(token_id: uncensored_greats_dao::candid::Nat, prev: Option<TokenApproval>, take: Option<uncensored_greats_dao::candid::Nat>) -> Vec<TokenApproval> {
+                match #ident::token_approvals(token_id.0.to_u64().unwrap_or(0), match prev{
+                    Some(p) => Some(p.approval_info.spender),
+                    None => None
+                }, match take{
+                    Some(s) => Some(s.0.to_u64().unwrap_or(0) as usize),
+                    None => None
+                }){
+                    Ok(m) => {
+                        m.into_iter().map(|(spender, from, approval)| TokenApproval{
+                            token_id: token_id.clone(),
+                            approval_info: ApprovalInfo{
+                                spender,
+                                from_subaccount: from.subaccount,
+                                expires_at: approval.expires_at,
+                                memo: approval.memo,
+                                created_at_time: approval.created_at,
+                            },
+                        }).collect()
+                    }
+                    Err(e) => {
+                        uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+                    }
+                }
+            }
+
+            #[uncensored_greats_dao::ic_cdk::query]
+            pub fn icrc37_get_collection_approvals(owner: uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account, prev: Option<ApprovalInfo>, take: Option<uncensored_greats_dao::candid::Nat>) -> Vec<ApprovalInfo> {
+                match #ident::collection_approvals(owner, match prev{
+                    Some(p) => Some(p.spender),
+                    None => None
+                }, match take{
+                    Some(s) => Some(s.0.to_u64().unwrap_or(0) as usize),
+                    None => None
+                }){
+                    Ok(m) => {
+                        m.into_iter().map(|(spender, approval)| ApprovalInfo{
+                            spender,
+                            from_subaccount: owner.subaccount,
+                            expires_at: approval.expires_at,
+                            memo: approval.memo,
+                            created_at_time: approval.created_at,
+                        }).collect()
+                    }
+                    Err(e) => {
+                        uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+                    }
+                }
+            }
+
+            #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+            pub struct TransferFromArg {
+                pub spender_subaccount: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Subaccount>,
+                pub from: uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account,
+                pub to: uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account,
+                pub token_id: uncensored_greats_dao::candid::Nat,
+                pub memo: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo>,
+                pub created_at_time: Option<u64>,
+            }
+
+            #[uncensored_greats_dao::ic_cdk::update]
+            pub fn icrc37_transfer_from(
+                args: Vec<TransferFromArg>,
+            ) -> Vec<Option<std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::TransferError>>> {
+                match #ident::transfer_from(args.into_iter().map(|arg| (
+                    arg.spender_subaccount,
+                    arg.from,
+                    arg.to,
+                    arg.token_id.0.to_u64().unwrap_or(0),
+                    arg.memo,
+                    arg.created_at_time
+                )).collect()){
+                    Ok(m) => {
+                        m.into_iter().map(|i| Some(i.map(|ii| ii.into()))).collect()
+                    }
+                    Err(e) => vec![Some(Err(e))],
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+    for standard in opts.supported_standard {
+        let url = standard.url;
+        standard_names.push(standard.name);
+        standard_urls.push(quote! { #url });
+    }
+    let collection_metadata = opts.collection_metadata.iter().map(|m| {
+        let key = &m.key;
+        let value = match (&m.text, m.nat, m.int) {
+            (Some(t), None, None) => quote! { uncensored_greats_dao::Value::Text(#t.to_string()) },
+            (None, Some(n), None) => quote! { uncensored_greats_dao::Value::Nat(#n.into()) },
+            (None, None, Some(i)) => quote! { uncensored_greats_dao::Value::Int(#i.into()) },
+            _ => panic!(
+                "collection_metadata `{}` needs exactly one of text, nat or int",
+                key
+            ),
+        };
+        quote! { (#key.to_string(), #value) }
+    });
+    let authorized_burners = if opts.burner.is_empty() {
+        quote! {}
+    } else {
+        let burners = opts.burner.iter().map(|burner| {
+            let bytes = ic_principal::Principal::from_text(burner)
+                .unwrap_or_else(|e| panic!("invalid burner principal `{burner}`: {e}"))
+                .as_slice()
+                .to_vec();
+            quote! { uncensored_greats_dao::candid::Principal::from_slice(&[#(#bytes),*]) }
+        });
+        quote! {
+            fn authorized_burners() -> Vec<uncensored_greats_dao::candid::Principal> {
+                vec![#(#burners),*]
+            }
+        }
+    };
     let output = quote! {
         impl uncensored_greats_dao::Icrc7<#token_type> for #ident {
             fn symbol() -> &'static str{
@@ -138,12 +584,59 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
             #atomic_batch_transfers
             #tx_window
             #permitted_drift
+            #authorized_burners
+            fn extra_collection_metadata() -> Vec<(String, uncensored_greats_dao::Value)> {
+                vec![#(#collection_metadata),*]
+            }
+        }
+
+        impl uncensored_greats_dao::Icrc3 for #ident {
+            fn supported_block_types() -> Vec<(&'static str, &'static str)> {
+                vec![#((#block_types, #block_type_urls)),*]
+            }
+        }
+
+        impl uncensored_greats_dao::Icrc7Jobs<#token_type> for #ident {
+            #job_instruction_budget
+        }
+
+        impl uncensored_greats_dao::Icrc10 for #ident {
+            fn supported_standards() -> Vec<(&'static str, &'static str)> {
+                vec![#((#standard_names, #standard_urls)),*]
+            }
         }
 
         use uncensored_greats_dao::ic_cdk;
         use uncensored_greats_dao::candid;
+        use uncensored_greats_dao::Icrc3 as _;
+        use uncensored_greats_dao::Icrc10 as _;
+        use uncensored_greats_dao::Icrc37 as _;
+        use uncensored_greats_dao::Icrc7Jobs as _;
         use uncensored_greats_dao::num_traits::cast::ToPrimitive;
 
+        #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
+        pub struct SupportedStandard {
+            pub name: String,
+            pub url: String,
+        }
+
+        #[uncensored_greats_dao::ic_cdk::query]
+        pub fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+            #ident::supported_standards().into_iter().map(|(name, url)| SupportedStandard{
+                name: name.to_string(),
+                url: url.to_string(),
+            }).collect()
+        }
+
+        #[uncensored_greats_dao::ic_cdk::query]
+        pub fn icrc7_supported_standards() -> Vec<SupportedStandard> {
+            icrc10_supported_standards()
+        }
+
+        #icrc3
+
+        #icrc37
+
         #[uncensored_greats_dao::ic_cdk::query]
         pub fn icrc7_symbol() -> String {
             #ident::symbol().to_string()
@@ -218,11 +711,14 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
         }
 
         #[uncensored_greats_dao::ic_cdk::query]
-        pub fn icrc7_token_metadata(token_ids: Vec<uncensored_greats_dao::candid::Nat>) -> Vec<String> {
+        pub fn icrc7_collection_metadata() -> Vec<(String, uncensored_greats_dao::Value)> {
+            #ident::collection_metadata()
+        }
+
+        #[uncensored_greats_dao::ic_cdk::query]
+        pub fn icrc7_token_metadata(token_ids: Vec<uncensored_greats_dao::candid::Nat>) -> Vec<Option<Vec<(String, uncensored_greats_dao::Value)>>> {
             match #ident::token_metadata(token_ids.into_iter().map(|i| i.0.to_u64().unwrap_or(0)).collect()){
-                Ok(map) => {
-                    map
-                }
+                Ok(map) => map,
                 Err(e) => {
                     uncensored_greats_dao::ic_cdk::trap(&e.to_string());
                 }
@@ -232,12 +728,17 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
         #[uncensored_greats_dao::ic_cdk::query]
         pub fn icrc7_owner_of(token_ids: Vec<uncensored_greats_dao::candid::Nat>) -> Vec<Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>> {
             match #ident::owner_of(token_ids.into_iter().map(|i| i.0.to_u64().unwrap_or(0)).collect()){
-                Ok(map) => {
-                    map.into_iter().map(|p| match p{
-                        Some(pp) => Some(uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account{owner: pp, subaccount: None}),
-                        None => None
-                    }).collect()
+                Ok(map) => map,
+                Err(e) => {
+                    uncensored_greats_dao::ic_cdk::trap(&e.to_string());
                 }
+            }
+        }
+
+        #[uncensored_greats_dao::ic_cdk::query]
+        pub fn creator_of(token_ids: Vec<uncensored_greats_dao::candid::Nat>) -> Vec<Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>> {
+            match #ident::creator_of(token_ids.into_iter().map(|i| i.0.to_u64().unwrap_or(0)).collect()){
+                Ok(map) => map,
                 Err(e) => {
                     uncensored_greats_dao::ic_cdk::trap(&e.to_string());
                 }
@@ -246,7 +747,7 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
 
         #[uncensored_greats_dao::ic_cdk::query]
         pub fn icrc7_balance_of(accounts: Vec<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>) -> Vec<uncensored_greats_dao::candid::Nat> {
-            match #ident::balance_of(accounts.into_iter().map(|a| a.owner).collect()){
+            match #ident::balance_of(accounts){
                 Ok(m) => {
                     m.into_iter().map(|i| i.into()).collect()
                 }
@@ -258,13 +759,7 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
 
         #[uncensored_greats_dao::ic_cdk::query]
         pub fn icrc7_tokens(prev: Option<uncensored_greats_dao::candid::Nat>, take: Option<uncensored_greats_dao::candid::Nat>) -> Vec<uncensored_greats_dao::candid::Nat> {
-            match #ident::tokens(match prev{
-                Some(s) => Some(s.0.to_u64().unwrap_or(0) as usize),
-                None => None
-            }, match take{
-                Some(s) => Some(s.0.to_u64().unwrap_or(0) as usize),
-                None => None
-            }){
+            match #ident::tokens(prev.map(|s| s.0.to_u64().unwrap_or(u64::MAX)), take.map(|s| s.0.to_usize().unwrap_or(usize::MAX))){
                 Ok(m) => {
                     m.into_iter().map(|i| i.into()).collect()
                 }
@@ -277,13 +772,7 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
 
         #[uncensored_greats_dao::ic_cdk::query]
         pub fn icrc7_tokens_of(account: uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account, prev: Option<uncensored_greats_dao::candid::Nat>, take: Option<uncensored_greats_dao::candid::Nat>) -> Vec<uncensored_greats_dao::candid::Nat> {
-            match #ident::tokens_of(account.owner, match prev{
-                Some(s) => Some(s.0.to_u64().unwrap_or(0) as usize),
-                None => None
-            }, match take{
-                Some(s) => Some(s.0.to_u64().unwrap_or(0) as usize),
-                None => None
-            }){
+            match #ident::tokens_of(account, prev.map(|s| s.0.to_u64().unwrap_or(u64::MAX)), take.map(|s| s.0.to_usize().unwrap_or(usize::MAX))){
                 Ok(m) => {
                     m.into_iter().map(|i| i.into()).collect()
                 }
@@ -295,6 +784,7 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
 
         #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone, Debug)]
         pub struct TransferArg {
+            pub from_subaccount: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Subaccount>,
             pub to: uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account,
             pub token_id: uncensored_greats_dao::candid::Nat,
             pub memo: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo>,
@@ -304,46 +794,64 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
         #[uncensored_greats_dao::ic_cdk::update]
         pub fn icrc7_transfer(
             args: Vec<TransferArg>,
-        ) -> Vec<std::result::Result<uncensored_greats_dao::candid::Nat, String>> {
+        ) -> Vec<Option<std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::TransferError>>> {
             match #ident::transfer(args.into_iter().map(|arg| (
                 arg.token_id.0.to_u64().unwrap_or(0),
-                arg.to.owner,
+                arg.from_subaccount,
+                arg.to,
                 arg.memo,
                 arg.created_at_time
             )).collect()){
                 Ok(m) => {
-                    m.into_iter().map(|i| match i{
-                        Ok(ii) => Ok(ii.into()),
-                        Err(e) => Err(e.to_string())
-                    }).collect()
+                    m.into_iter().map(|i| Some(i.map(|ii| ii.into()))).collect()
                 }
-                Err(e) => {
-                    uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+                Err(e) => vec![Some(Err(e))],
+            }
+        }
+
+        #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone)]
+        pub struct BurnArg {
+            pub from_subaccount: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Subaccount>,
+            pub holder: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>,
+            pub token_id: uncensored_greats_dao::candid::Nat,
+            pub memo: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo>,
+            pub created_at_time: Option<u64>,
+        }
+
+        #[uncensored_greats_dao::ic_cdk::update]
+        pub fn icrc7_burn(
+            args: Vec<BurnArg>,
+        ) -> Vec<Option<std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::BurnError>>> {
+            match #ident::burn(args.into_iter().map(|arg| (
+                arg.token_id.0.to_u64().unwrap_or(0),
+                arg.from_subaccount,
+                arg.holder,
+                arg.memo,
+                arg.created_at_time
+            )).collect()){
+                Ok(m) => {
+                    m.into_iter().map(|i| Some(i.map(|ii| ii.into()))).collect()
                 }
+                Err(e) => vec![Some(Err(e))],
             }
         }
 
         #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone)]
         pub struct MintArg {
-            pub token_id: uncensored_greats_dao::candid::Nat,
-            pub holders: std::collections::HashSet<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>,
+            pub definition_id: uncensored_greats_dao::candid::Nat,
+            pub holders: Vec<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>,
+            pub memo: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo>,
+            pub created_at_time: Option<u64>,
         }
         #[uncensored_greats_dao::ic_cdk::update]
         pub fn mint(
             args: MintArg,
-        ) -> Vec<std::result::Result<uncensored_greats_dao::candid::Nat, String>> {
-            match #ident::mint(args.token_id.0.to_u64().unwrap_or(0), args.holders.into_iter().map(|arg| (
-                arg.owner
-            )).collect()){
+        ) -> Vec<std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::MintError>> {
+            match #ident::mint(args.definition_id.0.to_u64().unwrap_or(0), args.holders, args.memo, args.created_at_time){
                 Ok(m) => {
-                    m.into_iter().map(|i| match i{
-                        Ok(ii) => Ok(ii.into()),
-                        Err(e) => Err(e.to_string())
-                    }).collect()
-                }
-                Err(e) => {
-                    uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+                    m.into_iter().map(|i| i.map(|ii| ii.into())).collect()
                 }
+                Err(e) => vec![Err(e)],
             }
         }
 
@@ -351,6 +859,7 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
         pub struct CreateArg {
             pub token: #token_type,
             pub supply_cap: Option<uncensored_greats_dao::candid::Nat>,
+            pub id: Option<uncensored_greats_dao::IdAllocation>,
         }
         #[uncensored_greats_dao::ic_cdk::update]
         pub fn create_token(
@@ -359,7 +868,7 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
             match #ident::create_token(args.token, match args.supply_cap{
                 Some(s) => Some(s.0.to_u64().unwrap_or(0) as usize),
                 None => None
-            }){
+            }, args.id.unwrap_or_default()){
                 Ok(m) => m.into(),
                 Err(e) => {
                     uncensored_greats_dao::ic_cdk::trap(&e.to_string());
@@ -369,7 +878,7 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
 
         #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone)]
         pub struct UpdateArg {
-            pub token_id: uncensored_greats_dao::candid::Nat,
+            pub definition_id: uncensored_greats_dao::candid::Nat,
             pub token: #token_type,
             pub supply_cap: Option<uncensored_greats_dao::candid::Nat>,
         }
@@ -377,7 +886,7 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
         pub fn update_token(
             args: UpdateArg
         ){
-            match #ident::update_token(args.token_id.0.to_u64().unwrap_or(0), args.token, match args.supply_cap{
+            match #ident::update_token(args.definition_id.0.to_u64().unwrap_or(0), args.token, match args.supply_cap{
                 Some(s) => Some(s.0.to_u64().unwrap_or(0) as usize),
                 None => None
             }){
@@ -388,8 +897,117 @@ pub fn derive_icrc7(input: TokenStream) -> TokenStream {
             }
         }
 
+        #[uncensored_greats_dao::ic_cdk::update]
+        pub fn delete_token(definition_id: uncensored_greats_dao::candid::Nat) {
+            if let Err(e) = #ident::delete_token(definition_id.0.to_u64().unwrap_or(0)) {
+                uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+            }
+        }
+
+        #[uncensored_greats_dao::ic_cdk::query]
+        pub fn token_by_asset_hash(hash: uncensored_greats_dao::serde_bytes::ByteBuf) -> Option<uncensored_greats_dao::candid::Nat> {
+            let hash = hash.into_vec().try_into().ok()?;
+            <#ident as uncensored_greats_dao::Icrc7AssetsStorage>::asset_token(hash).map(|id| id.into())
+        }
+
+        #[uncensored_greats_dao::ic_cdk::query]
+        pub fn quarantined_records(
+            prev: Option<(uncensored_greats_dao::RecordStore, uncensored_greats_dao::candid::Nat)>,
+            take: Option<uncensored_greats_dao::candid::Nat>,
+        ) -> Vec<uncensored_greats_dao::QuarantinedRecord> {
+            match #ident::quarantined_records(
+                prev.map(|(store, key)| (store, key.0.to_u64().unwrap_or(u64::MAX))),
+                take.map(|s| s.0.to_usize().unwrap_or(usize::MAX)),
+            ) {
+                Ok(records) => records,
+                Err(e) => {
+                    uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+                }
+            }
+        }
+
+        #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone)]
+        pub struct RepairArg {
+            pub store: uncensored_greats_dao::RecordStore,
+            pub key: uncensored_greats_dao::candid::Nat,
+            pub bytes: Option<uncensored_greats_dao::serde_bytes::ByteBuf>,
+        }
+        #[uncensored_greats_dao::ic_cdk::update]
+        pub fn repair_record(args: RepairArg) {
+            if let Err(e) = #ident::repair_record(
+                args.store,
+                args.key.0.to_u64().unwrap_or(u64::MAX),
+                args.bytes.map(|b| b.into_vec()),
+            ) {
+                uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+            }
+        }
+
+        #[derive(uncensored_greats_dao::candid::CandidType, Deserialize, Clone)]
+        pub struct MintJobArg {
+            pub definition_id: uncensored_greats_dao::candid::Nat,
+            pub holders: Vec<uncensored_greats_dao::icrc_ledger_types::icrc1::account::Account>,
+            pub memo: Option<uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo>,
+        }
+        #[uncensored_greats_dao::ic_cdk::update]
+        pub fn mint_job(
+            args: MintJobArg,
+        ) -> std::result::Result<uncensored_greats_dao::candid::Nat, uncensored_greats_dao::MintError> {
+            #ident::mint_job(args.definition_id.0.to_u64().unwrap_or(0), args.holders, args.memo).map(|id| id.into())
+        }
+
+        #[uncensored_greats_dao::ic_cdk::update]
+        pub fn reindex_job() -> uncensored_greats_dao::candid::Nat {
+            match #ident::reindex_job() {
+                Ok(id) => id.into(),
+                Err(e) => {
+                    uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+                }
+            }
+        }
+
+        #[uncensored_greats_dao::ic_cdk::update]
+        pub fn recount_job() -> uncensored_greats_dao::candid::Nat {
+            match #ident::recount_job() {
+                Ok(id) => id.into(),
+                Err(e) => {
+                    uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+                }
+            }
+        }
+
+        #[uncensored_greats_dao::ic_cdk::update(hidden = true)]
+        pub fn run_job_chunk(id: u64) {
+            if uncensored_greats_dao::ic_cdk::caller() != uncensored_greats_dao::ic_cdk::id() {
+                uncensored_greats_dao::ic_cdk::trap("job chunks are run by the canister itself");
+            }
+            #ident::run_job_chunk(id);
+        }
+
+        #[uncensored_greats_dao::ic_cdk::query]
+        pub fn job_status(id: uncensored_greats_dao::candid::Nat) -> Option<uncensored_greats_dao::JobStatus> {
+            #ident::job_status(id.0.to_u64()?)
+        }
+
+        #[uncensored_greats_dao::ic_cdk::update]
+        pub fn cancel_job(id: uncensored_greats_dao::candid::Nat) {
+            if let Err(e) = #ident::cancel_job(id.0.to_u64().unwrap_or(u64::MAX)) {
+                uncensored_greats_dao::ic_cdk::trap(&e.to_string());
+            }
+        }
+
         #[ic_cdk::init]
-        pub fn init(){}
+        pub fn init(){
+            #ident::start_dedup_pruning();
+        }
+
+        #[ic_cdk::post_upgrade]
+        pub fn post_upgrade(){
+            #ident::certify();
+            #ident::queue_migrations();
+            #ident::start_dedup_pruning();
+            #ident::schedule_jobs();
+        }
 
         ic_cdk::export_candid!();
     };
@@ -403,33 +1021,143 @@ pub fn derive_storage(input: TokenStream) -> TokenStream {
     let DeriveInput { ident, .. } = input;
     let tt = opts.token_type.clone();
     let token_type: proc_macro2::TokenStream = tt.parse().unwrap();
+    let memory = opts.memory.unwrap_or_default();
+    let layout = memory.layout();
+    let (memory_manager, memory_manager_def) = match &memory.manager {
+        Some(path) => (path.parse().expect("Wrong memory manager path"), quote! {}),
+        None => (
+            quote! { MEMORY_MANAGER },
+            quote! {
+                static MEMORY_MANAGER: std::cell::RefCell<uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryManager<uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl>> =
+                    std::cell::RefCell::new(uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryManager::init(uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl::default()));
+            },
+        ),
+    };
+    let get_memory = |name: &str| {
+        let id = layout.iter().find(|(n, _)| *n == name).unwrap().1;
+        quote! {
+            #memory_manager.with_borrow(|m| m.get(uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryId::new(#id)))
+        }
+    };
+    let tokens = get_memory("tokens");
+    let assets = get_memory("assets");
+    let transactions_index = get_memory("transactions_index");
+    let transactions_data = get_memory("transactions_data");
+    let token_approvals = get_memory("token_approvals");
+    let collection_approvals = get_memory("collection_approvals");
+    let dedup = get_memory("dedup");
+    let owner_index = get_memory("owner_index");
+    let supply = get_memory("supply");
+    let last_id = get_memory("last_id");
+    let holders = get_memory("holders");
+    let quarantine = get_memory("quarantine");
+    let jobs = get_memory("jobs");
+    let active_jobs = get_memory("active_jobs");
+    let definition_supply = get_memory("definition_supply");
+    let retired_ids = get_memory("retired_ids");
+    let layout_names = layout.iter().map(|(n, _)| n);
+    let layout_ids = layout.iter().map(|(_, id)| id);
     let output = quote! {
-        thread_local! {
-            static MEMORY_MANAGER: std::cell::RefCell<uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryManager<uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl>> =
-                std::cell::RefCell::new(uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryManager::init(uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl::default()));
+        impl #ident {
+            /// `MemoryId` of every stable structure the collection keeps.
+            pub const MEMORY_LAYOUT: &'static [(&'static str, u8)] = &[#((#layout_names, #layout_ids)),*];
+        }
 
+        thread_local! {
+            #memory_manager_def
 
-            static TOKENS: std::cell::RefCell<uncensored_greats_dao::ic_stable_structures::StableBTreeMap<u64, uncensored_greats_dao::TokenInner<#token_type>, uncensored_greats_dao::ic_stable_structures::memory_manager::VirtualMemory<uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl>>> = std::cell::RefCell::new(
+            static TOKENS: std::cell::RefCell<uncensored_greats_dao::TokenDefinitions<#token_type>> = std::cell::RefCell::new(
                 uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
-                    MEMORY_MANAGER.with_borrow(|m| m.get(uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryId::new(1)))
+                    #tokens
                 )
             );
 
-            static ASSETS: std::cell::RefCell<uncensored_greats_dao::ic_stable_structures::StableVec<u64, uncensored_greats_dao::ic_stable_structures::memory_manager::VirtualMemory<uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl>>> = std::cell::RefCell::new(
-                uncensored_greats_dao::ic_stable_structures::StableVec::init(
-                    MEMORY_MANAGER.with_borrow(|m| m.get(uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryId::new(2))),
-                ).expect("failed to init TOKENS store")
+            static ASSETS: std::cell::RefCell<uncensored_greats_dao::AssetIndex> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #assets
+                )
             );
 
-            static TRANSACTIONS: std::cell::RefCell<
-                    uncensored_greats_dao::ic_stable_structures::StableLog<uncensored_greats_dao::Transaction,
-                                                               uncensored_greats_dao::ic_stable_structures::memory_manager::VirtualMemory<uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl>,
-                                                               uncensored_greats_dao::ic_stable_structures::memory_manager::VirtualMemory<uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl>>> = std::cell::RefCell::new(
+            static TRANSACTIONS: std::cell::RefCell<uncensored_greats_dao::TransactionLog> = std::cell::RefCell::new(
                 uncensored_greats_dao::ic_stable_structures::StableLog::init(
-                    MEMORY_MANAGER.with_borrow(|m| m.get(uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryId::new(3))),
-                    MEMORY_MANAGER.with_borrow(|m| m.get(uncensored_greats_dao::ic_stable_structures::memory_manager::MemoryId::new(4))),
+                    #transactions_index,
+                    #transactions_data,
                 ).expect("failed to init BLOCKS store")
             );
+
+            static TOKEN_APPROVALS: std::cell::RefCell<uncensored_greats_dao::TokenApprovals> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #token_approvals
+                )
+            );
+
+            static COLLECTION_APPROVALS: std::cell::RefCell<uncensored_greats_dao::CollectionApprovals> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #collection_approvals
+                )
+            );
+
+            static DEDUP: std::cell::RefCell<uncensored_greats_dao::DedupIndex> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #dedup
+                )
+            );
+
+            static OWNER_INDEX: std::cell::RefCell<uncensored_greats_dao::OwnerIndex> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #owner_index
+                )
+            );
+
+            static SUPPLY: std::cell::RefCell<uncensored_greats_dao::SupplyCell> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableCell::init(
+                    #supply,
+                    Default::default(),
+                ).expect("failed to init SUPPLY cell")
+            );
+
+            static DEFINITION_SUPPLY: std::cell::RefCell<uncensored_greats_dao::DefinitionSupply> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #definition_supply
+                )
+            );
+
+            static LAST_ID: std::cell::RefCell<uncensored_greats_dao::IdCell> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableCell::init(
+                    #last_id,
+                    0,
+                ).expect("failed to init LAST_ID cell")
+            );
+
+            static RETIRED_IDS: std::cell::RefCell<uncensored_greats_dao::RetiredIds> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #retired_ids
+                )
+            );
+
+            static HOLDERS: std::cell::RefCell<uncensored_greats_dao::Holders> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #holders
+                )
+            );
+
+            static QUARANTINE: std::cell::RefCell<uncensored_greats_dao::Quarantine> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #quarantine
+                )
+            );
+
+            static JOBS: std::cell::RefCell<uncensored_greats_dao::Jobs> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #jobs
+                )
+            );
+
+            static ACTIVE_JOBS: std::cell::RefCell<uncensored_greats_dao::ActiveJobs> = std::cell::RefCell::new(
+                uncensored_greats_dao::ic_stable_structures::StableBTreeMap::init(
+                    #active_jobs
+                )
+            );
         }
 
         impl uncensored_greats_dao::ic_stable_structures::Storable for #ident{
@@ -448,35 +1176,154 @@ pub fn derive_storage(input: TokenStream) -> TokenStream {
         impl uncensored_greats_dao::Storage<#token_type> for #ident {}
 
         impl uncensored_greats_dao::Icrc7TokenStorage<#token_type> for #ident {
-            fn get_tokens(
-            ) -> &'static std::thread::LocalKey<
-                    std::cell::RefCell<
-                            uncensored_greats_dao::ic_stable_structures::StableBTreeMap<
-                                    u64,
-                                uncensored_greats_dao::TokenInner<#token_type>,
-                                uncensored_greats_dao::ic_stable_structures::memory_manager::VirtualMemory<
-                                        uncensored_greats_dao::ic_stable_structures::DefaultMemoryImpl,
-                                    >,
-                                >,
-                        >,
-                >{
+            fn get_tokens() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::TokenDefinitions<#token_type>>> {
                 &TOKENS
             }
         }
 
-        impl uncensored_greats_dao::Icrc7AssetsStorage for #ident {
-            fn check_asset(asset: u64) -> bool{
-                ASSETS.with(|r| r.borrow().iter().any(|s| s == asset))
+        impl uncensored_greats_dao::Icrc7QuarantineStorage for #ident {
+            fn get_quarantine() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::Quarantine>> {
+                &QUARANTINE
             }
-            fn add_asset(asset: u64) -> uncensored_greats_dao::Result<()>{
-                Ok(ASSETS.with(|r| r.borrow_mut().push(&asset))?)
+        }
+
+        impl uncensored_greats_dao::Icrc7JobStorage for #ident {
+            fn get_jobs() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::Jobs>> {
+                &JOBS
+            }
+            fn get_active_jobs() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::ActiveJobs>> {
+                &ACTIVE_JOBS
+            }
+        }
+
+        impl uncensored_greats_dao::Icrc7AssetsStorage for #ident {
+            fn get_assets() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::AssetIndex>> {
+                &ASSETS
             }
         }
+
         impl uncensored_greats_dao::Icrc7TransactionStorage for #ident {
-            fn add_transaction(transaction: uncensored_greats_dao::Transaction) -> uncensored_greats_dao::Result<u64>{
-                Ok(TRANSACTIONS.with(|r| r.borrow_mut().append(&transaction)).map_err(|_| uncensored_greats_dao::Error::Custom("failed to wrote log"))? )
+            fn get_transactions() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::TransactionLog>> {
+                &TRANSACTIONS
             }
        }
+
+        impl uncensored_greats_dao::Icrc7DedupStorage for #ident {
+            fn get_dedup() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::DedupIndex>> {
+                &DEDUP
+            }
+        }
+
+        impl uncensored_greats_dao::Icrc7OwnerStorage for #ident {
+            fn get_owner_index() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::OwnerIndex>> {
+                &OWNER_INDEX
+            }
+        }
+
+        impl uncensored_greats_dao::Icrc7HolderStorage for #ident {
+            fn get_holders() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::Holders>> {
+                &HOLDERS
+            }
+        }
+
+        impl uncensored_greats_dao::Icrc7SupplyStorage for #ident {
+            fn get_supply() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::SupplyCell>> {
+                &SUPPLY
+            }
+            fn get_definition_supply() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::DefinitionSupply>> {
+                &DEFINITION_SUPPLY
+            }
+        }
+
+        impl uncensored_greats_dao::Icrc7IdStorage for #ident {
+            fn get_last_id() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::IdCell>> {
+                &LAST_ID
+            }
+            fn get_retired_ids() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::RetiredIds>> {
+                &RETIRED_IDS
+            }
+        }
+
+        impl uncensored_greats_dao::Icrc37ApprovalStorage for #ident {
+            fn get_token_approvals() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::TokenApprovals>> {
+                &TOKEN_APPROVALS
+            }
+            fn get_collection_approvals() -> &'static std::thread::LocalKey<std::cell::RefCell<uncensored_greats_dao::CollectionApprovals>> {
+                &COLLECTION_APPROVALS
+            }
+        }
     };
     output.into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryOpts;
+
+    fn id(layout: &[(&str, u8)], name: &str) -> u8 {
+        layout.iter().find(|(n, _)| *n == name).unwrap().1
+    }
+
+    #[test]
+    fn default_layout_keeps_legacy_ids() {
+        let layout = MemoryOpts::default().layout();
+        assert_eq!(id(&layout, "tokens"), 1);
+        assert_eq!(id(&layout, "assets"), 10);
+        assert_eq!(id(&layout, "retired_ids"), 17);
+        assert!(layout.iter().all(|(_, id)| *id != 2));
+    }
+
+    #[test]
+    fn base_packs_structures_and_overrides_win() {
+        let layout = MemoryOpts {
+            base: Some(20),
+            holders: Some(40),
+            ..Default::default()
+        }
+        .layout();
+        assert_eq!(id(&layout, "tokens"), 20);
+        assert_eq!(id(&layout, "assets"), 21);
+        assert_eq!(id(&layout, "retired_ids"), 35);
+        assert_eq!(id(&layout, "holders"), 40);
+    }
+
+    #[test]
+    #[should_panic(expected = "holders and tokens both use memory id 1")]
+    fn overlapping_ids_panic() {
+        MemoryOpts {
+            holders: Some(1),
+            ..Default::default()
+        }
+        .layout();
+    }
+
+    #[test]
+    #[should_panic(expected = "jobs uses reserved memory id 14")]
+    fn reserved_ids_panic() {
+        MemoryOpts {
+            reserved: vec![14],
+            ..Default::default()
+        }
+        .layout();
+    }
+
+    #[test]
+    #[should_panic(expected = "memory id of jobs must be below 255")]
+    fn unallocated_marker_panics() {
+        MemoryOpts {
+            jobs: Some(255),
+            ..Default::default()
+        }
+        .layout();
+    }
+
+    #[test]
+    #[should_panic(expected = "memory base leaves no room for every structure")]
+    fn base_overflow_panics() {
+        MemoryOpts {
+            base: Some(250),
+            ..Default::default()
+        }
+        .layout();
+    }
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use uncensored_greats_dao::{Icrc7, Metadata, Storage};
+use uncensored_greats_dao::{Icrc7, Metadata, Storage, Value};
 
 #[derive(
     uncensored_greats_dao::candid::CandidType, Clone, Hash, Default, Serialize, Deserialize,
@@ -10,12 +10,12 @@ pub struct TestToken {
 }
 
 impl Metadata for TestToken {
-    fn metadata(&self) -> String {
-        serde_json::to_string(&serde_json::json!({
-            "name": self.name,
-            "description": self.description
-        }))
-        .unwrap_or_default()
+    fn metadata(&self) -> Vec<(String, Value)> {
+        let mut metadata = vec![("name".to_string(), Value::Text(self.name.clone()))];
+        if let Some(description) = &self.description {
+            metadata.push(("description".to_string(), Value::Text(description.clone())));
+        }
+        metadata
     }
 }
 
@@ -23,4 +23,5 @@ impl Metadata for TestToken {
 #[icrc7(token_type = "TestToken")]
 #[icrc7(symbol = "TT")]
 #[icrc7(name = "Test Token")]
+#[icrc7(collection_metadata(key = "website", text = "https://example.com"))]
 pub struct TokenCollections {}
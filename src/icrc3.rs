@@ -0,0 +1,326 @@
+use crate::{Icrc7TransactionStorage, Transaction};
+use candid::Nat;
+use ciborium::Value as Cbor;
+use icrc_ledger_types::icrc::generic_value::{Hash, ICRC3Value};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc3::blocks::{
+    BlockWithId, GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate,
+};
+use num_traits::ToPrimitive;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+fn account(account: Account) -> ICRC3Value {
+    let mut parts = vec![ICRC3Value::Blob(ByteBuf::from(account.owner.as_slice()))];
+    if let Some(subaccount) = account.subaccount.filter(|s| *s != [0; 32]) {
+        parts.push(ICRC3Value::Blob(ByteBuf::from(subaccount)));
+    }
+    ICRC3Value::Array(parts)
+}
+
+impl Transaction {
+    /// Encodes the transaction as an ICRC-3 block following the ICRC-7 and
+    /// ICRC-37 block schemas.
+    pub fn to_block(&self) -> ICRC3Value {
+        let mut tx = BTreeMap::new();
+        if let Some(tid) = self.token_id {
+            tx.insert("tid".to_string(), ICRC3Value::Nat(tid.into()));
+        }
+        if let Some(from) = self.from {
+            tx.insert("from".to_string(), account(from));
+        }
+        if let Some(to) = self.to {
+            tx.insert("to".to_string(), account(to));
+        }
+        if let Some(spender) = self.spender {
+            tx.insert("spender".to_string(), account(spender));
+        }
+        if let Some(exp) = self.exp {
+            tx.insert("exp".to_string(), ICRC3Value::Nat(exp.into()));
+        }
+        if let Some(meta) = &self.meta {
+            tx.insert("meta".to_string(), meta.clone());
+        }
+        if let Some(memo) = &self.memo {
+            tx.insert("memo".to_string(), ICRC3Value::Blob(memo.0.clone()));
+        }
+        let mut block = BTreeMap::new();
+        if let Some(phash) = self.phash {
+            block.insert("phash".to_string(), ICRC3Value::Blob(ByteBuf::from(phash)));
+        }
+        block.insert("btype".to_string(), ICRC3Value::Text(self.op.clone()));
+        block.insert("ts".to_string(), ICRC3Value::Nat(self.ts.into()));
+        block.insert("tx".to_string(), ICRC3Value::Map(tx));
+        ICRC3Value::Map(block)
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.to_block().hash()
+    }
+}
+
+/// The ICRC-3 certified tree: `last_block_hash` and `last_block_index` under
+/// the root, with every node kept as `(cbor, digest)`.
+fn tip_tree(index: u64, hash: Hash) -> (Cbor, Hash) {
+    fn domain_sep(hasher: &mut Sha256, sep: &str) {
+        hasher.update([sep.len() as u8]);
+        hasher.update(sep.as_bytes());
+    }
+    fn leaf(data: Vec<u8>) -> (Cbor, Hash) {
+        let mut h = Sha256::new();
+        domain_sep(&mut h, "ic-hashtree-leaf");
+        h.update(&data);
+        (
+            Cbor::Array(vec![3.into(), Cbor::Bytes(data)]),
+            h.finalize().into(),
+        )
+    }
+    fn labeled(label: &str, (tree, digest): (Cbor, Hash)) -> (Cbor, Hash) {
+        let mut h = Sha256::new();
+        domain_sep(&mut h, "ic-hashtree-labeled");
+        h.update(label.as_bytes());
+        h.update(digest);
+        let label = Cbor::Bytes(label.as_bytes().to_vec());
+        (
+            Cbor::Array(vec![2.into(), label, tree]),
+            h.finalize().into(),
+        )
+    }
+    fn fork((l, ld): (Cbor, Hash), (r, rd): (Cbor, Hash)) -> (Cbor, Hash) {
+        let mut h = Sha256::new();
+        domain_sep(&mut h, "ic-hashtree-fork");
+        h.update(ld);
+        h.update(rd);
+        (Cbor::Array(vec![1.into(), l, r]), h.finalize().into())
+    }
+    let mut leb = vec![];
+    let mut n = index;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            leb.push(byte);
+            break;
+        }
+        leb.push(byte | 0x80);
+    }
+    fork(
+        labeled("last_block_hash", leaf(hash.to_vec())),
+        labeled("last_block_index", leaf(leb)),
+    )
+}
+
+/// Points the canister certified data at the given tip of the log.
+pub(crate) fn certify_tip(index: u64, hash: Hash) {
+    ic_cdk::api::set_certified_data(&tip_tree(index, hash).1);
+}
+
+pub trait Icrc3: Icrc7TransactionStorage {
+    fn max_blocks_per_request() -> usize {
+        100
+    }
+    /// Block types the log holds, ICRC-37 ones only when ICRC-37 is enabled.
+    fn supported_block_types() -> Vec<(&'static str /*block_type*/, &'static str /*url*/)>;
+    /// Index and hash of the last block in the log.
+    fn tip() -> Option<(u64, Hash)> {
+        let index = Self::get_transactions()
+            .with(|k| k.borrow().len())
+            .checked_sub(1)?;
+        let tx = Self::get_transaction(index)?.ok()?;
+        Some((index, tx.hash()))
+    }
+    /// Re-certifies the current tip, certified data does not survive upgrades.
+    fn certify() {
+        if let Some((index, hash)) = Self::tip() {
+            certify_tip(index, hash);
+        }
+    }
+    fn tip_certificate() -> Option<ICRC3DataCertificate> {
+        let certificate = ic_cdk::api::data_certificate()?;
+        let (index, hash) = Self::tip()?;
+        let mut hash_tree = vec![];
+        ciborium::into_writer(
+            &Cbor::Tag(55799, Box::new(tip_tree(index, hash).0)),
+            &mut hash_tree,
+        )
+        .ok()?;
+        Some(ICRC3DataCertificate {
+            certificate: ByteBuf::from(certificate),
+            hash_tree: ByteBuf::from(hash_tree),
+        })
+    }
+    fn get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+        let log_length = Self::get_transactions().with(|k| k.borrow().len());
+        let mut budget = Self::max_blocks_per_request() as u64;
+        let mut blocks = vec![];
+        for arg in args {
+            let start = arg.start.0.to_u64().unwrap_or(u64::MAX);
+            let length = arg.length.0.to_u64().unwrap_or(u64::MAX).min(budget);
+            let end = start.saturating_add(length).min(log_length);
+            for id in start..end {
+                if let Some(Ok(tx)) = Self::get_transaction(id) {
+                    blocks.push(BlockWithId {
+                        id: Nat::from(id),
+                        block: tx.to_block(),
+                    });
+                }
+            }
+            budget -= end.saturating_sub(start);
+        }
+        GetBlocksResult {
+            log_length: Nat::from(log_length),
+            blocks,
+            archived_blocks: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Principal;
+    use icrc_ledger_types::icrc1::transfer::Memo;
+
+    fn blob(bytes: &[u8]) -> ICRC3Value {
+        ICRC3Value::Blob(ByteBuf::from(bytes.to_vec()))
+    }
+
+    fn transaction() -> Transaction {
+        Transaction {
+            ts: 1_700_000_000_000_000_000,
+            token_id: Some(4_294_967_297),
+            op: "7xfr".to_string(),
+            from: Some(Principal::from_slice(&[1; 29]).into()),
+            to: None,
+            meta: None,
+            memo: None,
+            spender: None,
+            exp: None,
+            phash: None,
+            version: crate::SCHEMA_VERSION,
+        }
+    }
+
+    #[test]
+    fn block_maps_transaction_fields() {
+        let mut tx = transaction();
+        tx.to = Some(Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: Some([0; 32]),
+        });
+        tx.spender = Some(Account {
+            owner: Principal::from_slice(&[3; 29]),
+            subaccount: Some([4; 32]),
+        });
+        tx.exp = Some(9);
+        tx.memo = Some(Memo::from(vec![5, 6]));
+        tx.meta = Some(ICRC3Value::Text("meta".to_string()));
+        tx.phash = Some([8; 32]);
+        let expected = ICRC3Value::Map(
+            [
+                ("btype".to_string(), ICRC3Value::Text("7xfr".to_string())),
+                ("ts".to_string(), ICRC3Value::Nat(tx.ts.into())),
+                ("phash".to_string(), blob(&[8; 32])),
+                (
+                    "tx".to_string(),
+                    ICRC3Value::Map(
+                        [
+                            ("tid".to_string(), ICRC3Value::Nat(4_294_967_297u64.into())),
+                            ("from".to_string(), ICRC3Value::Array(vec![blob(&[1; 29])])),
+                            // the default subaccount is left out
+                            ("to".to_string(), ICRC3Value::Array(vec![blob(&[2; 29])])),
+                            (
+                                "spender".to_string(),
+                                ICRC3Value::Array(vec![blob(&[3; 29]), blob(&[4; 32])]),
+                            ),
+                            ("exp".to_string(), ICRC3Value::Nat(9u64.into())),
+                            ("meta".to_string(), ICRC3Value::Text("meta".to_string())),
+                            ("memo".to_string(), blob(&[5, 6])),
+                        ]
+                        .into(),
+                    ),
+                ),
+            ]
+            .into(),
+        );
+        assert_eq!(tx.to_block(), expected);
+        assert_eq!(tx.hash(), expected.hash());
+    }
+
+    #[test]
+    fn block_leaves_out_missing_fields() {
+        let mut tx = transaction();
+        tx.token_id = None;
+        tx.from = None;
+        let ICRC3Value::Map(block) = tx.to_block() else {
+            panic!("block is not a map")
+        };
+        assert_eq!(
+            block.keys().collect::<Vec<_>>(),
+            ["btype", "ts", "tx"].iter().collect::<Vec<_>>()
+        );
+        assert_eq!(block["tx"], ICRC3Value::Map(BTreeMap::new()));
+    }
+
+    fn labeled_leaf(tree: &Cbor) -> (String, Vec<u8>) {
+        let Cbor::Array(node) = tree else {
+            panic!("not a node: {tree:?}")
+        };
+        assert_eq!(node[0], Cbor::from(2));
+        let (Cbor::Bytes(label), Cbor::Array(leaf)) = (&node[1], &node[2]) else {
+            panic!("not a labeled leaf: {tree:?}")
+        };
+        assert_eq!(leaf[0], Cbor::from(3));
+        let Cbor::Bytes(data) = &leaf[1] else {
+            panic!("not a leaf: {tree:?}")
+        };
+        (String::from_utf8(label.clone()).unwrap(), data.clone())
+    }
+
+    fn index_leb(index: u64) -> Vec<u8> {
+        let (Cbor::Array(fork), _) = tip_tree(index, [0; 32]) else {
+            panic!("tip is not a fork")
+        };
+        let (label, leb) = labeled_leaf(&fork[2]);
+        assert_eq!(label, "last_block_index");
+        leb
+    }
+
+    #[test]
+    fn tip_index_is_leb128() {
+        assert_eq!(index_leb(0), [0x00]);
+        assert_eq!(index_leb(127), [0x7f]);
+        assert_eq!(index_leb(128), [0x80, 0x01]);
+        assert_eq!(index_leb(624_485), [0xe5, 0x8e, 0x26]);
+        assert_eq!(index_leb(u64::MAX).len(), 10);
+    }
+
+    #[test]
+    fn tip_tree_is_labeled_fork() {
+        let hash = [7; 32];
+        let (tree, digest) = tip_tree(1, hash);
+        let Cbor::Array(fork) = tree else {
+            panic!("tip is not a fork")
+        };
+        assert_eq!(fork[0], Cbor::from(1));
+        assert_eq!(
+            labeled_leaf(&fork[1]),
+            ("last_block_hash".to_string(), hash.to_vec())
+        );
+
+        let sha = |parts: &[&[u8]]| -> Hash {
+            let mut h = Sha256::new();
+            parts.iter().for_each(|p| h.update(p));
+            h.finalize().into()
+        };
+        let leaf = |data: &[u8]| sha(&[b"\x10ic-hashtree-leaf", data]);
+        let labeled = |label: &[u8], d: Hash| sha(&[b"\x13ic-hashtree-labeled", label, &d]);
+        let expected = sha(&[
+            b"\x10ic-hashtree-fork",
+            &labeled(b"last_block_hash", leaf(&hash)),
+            &labeled(b"last_block_index", leaf(&[1])),
+        ]);
+        assert_eq!(digest, expected);
+    }
+}
@@ -0,0 +1,708 @@
+use crate::{
+    batch_error, request_hash, AccountKey, Error, Icrc7, Memory, Metadata, Result, Transaction,
+    TransferError, Value, SCHEMA_VERSION,
+};
+use candid::{CandidType, Nat, Principal};
+use ciborium::{from_reader, into_writer};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::Memo;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::hash::Hash;
+use std::thread::LocalKey;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Approval {
+    pub expires_at: Option<u64>,
+    pub memo: Option<Memo>,
+    /// `created_at_time` of the approving call, if the caller sent one.
+    pub created_at: Option<u64>,
+}
+
+impl Approval {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|exp| exp > now)
+    }
+}
+
+impl Storable for Approval {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Approval data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Approval data")
+    }
+}
+
+#[derive(thiserror::Error, CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum ApproveTokenError {
+    #[error("invalid spender")]
+    InvalidSpender,
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("non existing token id")]
+    NonExistingTokenId,
+    #[error("too old")]
+    TooOld,
+    #[error("created in future, ledger time {ledger_time}")]
+    CreatedInFuture { ledger_time: u64 },
+    #[error("{message}")]
+    GenericError { error_code: Nat, message: String },
+    #[error("{message}")]
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+batch_error!(ApproveTokenError);
+
+#[derive(thiserror::Error, CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum ApproveCollectionError {
+    #[error("invalid spender")]
+    InvalidSpender,
+    #[error("too old")]
+    TooOld,
+    #[error("created in future, ledger time {ledger_time}")]
+    CreatedInFuture { ledger_time: u64 },
+    #[error("{message}")]
+    GenericError { error_code: Nat, message: String },
+    #[error("{message}")]
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+batch_error!(ApproveCollectionError);
+
+#[derive(thiserror::Error, CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum RevokeTokenApprovalError {
+    #[error("approval does not exist")]
+    ApprovalDoesNotExist,
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("non existing token id")]
+    NonExistingTokenId,
+    #[error("too old")]
+    TooOld,
+    #[error("created in future, ledger time {ledger_time}")]
+    CreatedInFuture { ledger_time: u64 },
+    #[error("{message}")]
+    GenericError { error_code: Nat, message: String },
+    #[error("{message}")]
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+batch_error!(RevokeTokenApprovalError);
+
+#[derive(thiserror::Error, CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum RevokeCollectionApprovalError {
+    #[error("approval does not exist")]
+    ApprovalDoesNotExist,
+    #[error("too old")]
+    TooOld,
+    #[error("created in future, ledger time {ledger_time}")]
+    CreatedInFuture { ledger_time: u64 },
+    #[error("{message}")]
+    GenericError { error_code: Nat, message: String },
+    #[error("{message}")]
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+batch_error!(RevokeCollectionApprovalError);
+
+/// Token level approvals keyed by `(token_id, spender, from)`.
+pub type TokenApprovals = StableBTreeMap<(u64, AccountKey, AccountKey), Approval, Memory>;
+/// Collection level approvals keyed by `(from, spender)`.
+pub type CollectionApprovals = StableBTreeMap<(AccountKey, AccountKey), Approval, Memory>;
+
+pub trait Icrc37ApprovalStorage {
+    fn get_token_approvals() -> &'static LocalKey<RefCell<TokenApprovals>>;
+    fn get_collection_approvals() -> &'static LocalKey<RefCell<CollectionApprovals>>;
+}
+
+fn token_approval_keys(
+    approvals: &TokenApprovals,
+    token_id: u64,
+) -> impl Iterator<Item = (u64, AccountKey, AccountKey)> + '_ {
+    approvals
+        .range((token_id, AccountKey::MIN, AccountKey::MIN)..)
+        .take_while(move |((id, _, _), _)| *id == token_id)
+        .map(|(key, _)| key)
+}
+
+fn collection_approval_keys(
+    approvals: &CollectionApprovals,
+    from: Account,
+) -> impl Iterator<Item = (AccountKey, AccountKey)> + '_ {
+    approvals
+        .range((AccountKey(from), AccountKey::MIN)..)
+        .take_while(move |((f, _), _)| f.0 == from)
+        .map(|(key, _)| key)
+}
+
+/// Drops every token level approval `from` has granted on `token_id`, called
+/// whenever `from` stops holding the token.
+pub(crate) fn clear_token_approvals<S>(token_id: u64, from: Account)
+where
+    S: Icrc37ApprovalStorage + ?Sized,
+{
+    S::get_token_approvals().with(|k| {
+        let mut approvals = k.borrow_mut();
+        let keys: Vec<_> = token_approval_keys(&approvals, token_id)
+            .filter(|(_, _, f)| f.0 == from)
+            .collect();
+        for key in keys {
+            approvals.remove(&key);
+        }
+    })
+}
+
+#[allow(clippy::type_complexity)]
+pub trait Icrc37<T>: Icrc7<T>
+where
+    T: Hash + Metadata + DeserializeOwned + Serialize + 'static,
+{
+    fn max_approvals_per_token_or_collection() -> usize {
+        10
+    }
+    fn max_revoke_approvals() -> usize {
+        10
+    }
+    /// The `icrc37:` limits, as returned by `icrc37_metadata`.
+    fn approval_metadata() -> Vec<(String, Value)> {
+        vec![
+            (
+                "icrc37:max_approvals_per_token_or_collection".to_string(),
+                Value::Nat(Nat::from(Self::max_approvals_per_token_or_collection())),
+            ),
+            (
+                "icrc37:max_revoke_approvals".to_string(),
+                Value::Nat(Nat::from(Self::max_revoke_approvals())),
+            ),
+        ]
+    }
+    fn approve_tokens(
+        args: Vec<(
+            u64,                /*token_id*/
+            Option<Subaccount>, /*from_subaccount*/
+            Account,            /*spender*/
+            Option<u64>,        /*expires_at*/
+            Option<Memo>,       /*memo*/
+            Option<u64>,        /*created_at*/
+        )>,
+    ) -> std::result::Result<Vec<std::result::Result<u64, ApproveTokenError>>, ApproveTokenError>
+    {
+        if args.is_empty() {
+            return Err(ApproveTokenError::batch(Error::Custom(
+                "no approve args provided",
+            )));
+        }
+        if args.len() > Self::max_update_batch_size() {
+            return Err(ApproveTokenError::batch(Error::Custom(
+                "exceeds max update batch size",
+            )));
+        }
+        Self::tip_hash().map_err(ApproveTokenError::batch)?;
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        Ok(args.into_iter().fold(vec![], |mut res, arg| {
+            let (token_id, from_subaccount, spender, expires_at, memo, created_at) = arg;
+            let from = Account {
+                owner: caller,
+                subaccount: from_subaccount,
+            };
+            let check = || {
+                if spender.owner == caller || spender.owner == Principal::anonymous() {
+                    return Err(ApproveTokenError::InvalidSpender);
+                }
+                if memo
+                    .as_ref()
+                    .is_some_and(|m| m.0.len() > Self::max_memo_size())
+                {
+                    return Err(Error::Custom("memo size too large").into());
+                }
+                if let Some(ct) = created_at {
+                    Self::check_created_at(ct, now)?;
+                }
+                if expires_at.is_some_and(|exp| exp <= now) {
+                    return Err(Error::Custom("approval already expired").into());
+                }
+                match Self::holder_of(token_id) {
+                    Some(holder) if holder == from => {}
+                    Some(_) => return Err(ApproveTokenError::Unauthorized),
+                    None => return Err(ApproveTokenError::NonExistingTokenId),
+                }
+                Self::get_token_approvals().with(|k| {
+                    let approvals = k.borrow();
+                    let granted = token_approval_keys(&approvals, token_id)
+                        .filter(|(_, _, f)| f.0 == from)
+                        .count();
+                    if granted >= Self::max_approvals_per_token_or_collection()
+                        && !approvals.contains_key(&(token_id, spender.into(), from.into()))
+                    {
+                        return Err(Error::Custom("too many approvals").into());
+                    }
+                    Ok(())
+                })
+            };
+            res.push(check().map(|_| {
+                Self::get_token_approvals().with(|k| {
+                    k.borrow_mut().insert(
+                        (token_id, spender.into(), from.into()),
+                        Approval {
+                            expires_at,
+                            memo: memo.clone(),
+                            created_at,
+                        },
+                    )
+                });
+                Self::add_transaction(Transaction {
+                    ts: now,
+                    token_id: Some(token_id),
+                    op: "37approve".to_string(),
+                    from: Some(from),
+                    to: None,
+                    meta: None,
+                    memo,
+                    spender: Some(spender),
+                    exp: expires_at,
+                    phash: None,
+                    version: SCHEMA_VERSION,
+                })
+            }));
+            res
+        }))
+    }
+    fn approve_collection(
+        args: Vec<(
+            Option<Subaccount>, /*from_subaccount*/
+            Account,            /*spender*/
+            Option<u64>,        /*expires_at*/
+            Option<Memo>,       /*memo*/
+            Option<u64>,        /*created_at*/
+        )>,
+    ) -> std::result::Result<
+        Vec<std::result::Result<u64, ApproveCollectionError>>,
+        ApproveCollectionError,
+    > {
+        if args.is_empty() {
+            return Err(ApproveCollectionError::batch(Error::Custom(
+                "no approve args provided",
+            )));
+        }
+        if args.len() > Self::max_update_batch_size() {
+            return Err(ApproveCollectionError::batch(Error::Custom(
+                "exceeds max update batch size",
+            )));
+        }
+        Self::tip_hash().map_err(ApproveCollectionError::batch)?;
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        Ok(args.into_iter().fold(vec![], |mut res, arg| {
+            let (from_subaccount, spender, expires_at, memo, created_at) = arg;
+            let from = Account {
+                owner: caller,
+                subaccount: from_subaccount,
+            };
+            let check = || {
+                if spender.owner == caller || spender.owner == Principal::anonymous() {
+                    return Err(ApproveCollectionError::InvalidSpender);
+                }
+                if memo
+                    .as_ref()
+                    .is_some_and(|m| m.0.len() > Self::max_memo_size())
+                {
+                    return Err(Error::Custom("memo size too large").into());
+                }
+                if let Some(ct) = created_at {
+                    Self::check_created_at(ct, now)?;
+                }
+                if expires_at.is_some_and(|exp| exp <= now) {
+                    return Err(Error::Custom("approval already expired").into());
+                }
+                Self::get_collection_approvals().with(|k| {
+                    let approvals = k.borrow();
+                    if collection_approval_keys(&approvals, from).count()
+                        >= Self::max_approvals_per_token_or_collection()
+                        && !approvals.contains_key(&(from.into(), spender.into()))
+                    {
+                        return Err(Error::Custom("too many approvals").into());
+                    }
+                    Ok(())
+                })
+            };
+            res.push(check().map(|_| {
+                Self::get_collection_approvals().with(|k| {
+                    k.borrow_mut().insert(
+                        (from.into(), spender.into()),
+                        Approval {
+                            expires_at,
+                            memo: memo.clone(),
+                            created_at,
+                        },
+                    )
+                });
+                Self::add_transaction(Transaction {
+                    ts: now,
+                    token_id: None,
+                    op: "37approve_coll".to_string(),
+                    from: Some(from),
+                    to: None,
+                    meta: None,
+                    memo,
+                    spender: Some(spender),
+                    exp: expires_at,
+                    phash: None,
+                    version: SCHEMA_VERSION,
+                })
+            }));
+            res
+        }))
+    }
+    fn revoke_token_approvals(
+        args: Vec<(
+            u64,                /*token_id*/
+            Option<Subaccount>, /*from_subaccount*/
+            Option<Account>,    /*spender*/
+            Option<Memo>,       /*memo*/
+            Option<u64>,        /*created_at*/
+        )>,
+    ) -> std::result::Result<
+        Vec<std::result::Result<u64, RevokeTokenApprovalError>>,
+        RevokeTokenApprovalError,
+    > {
+        if args.is_empty() {
+            return Err(RevokeTokenApprovalError::batch(Error::Custom(
+                "no revoke args provided",
+            )));
+        }
+        if args.len() > Self::max_revoke_approvals() {
+            return Err(RevokeTokenApprovalError::batch(Error::Custom(
+                "exceeds max revoke approvals",
+            )));
+        }
+        Self::tip_hash().map_err(RevokeTokenApprovalError::batch)?;
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        Ok(args.into_iter().fold(vec![], |mut res, arg| {
+            let (token_id, from_subaccount, spender, memo, created_at) = arg;
+            let from = Account {
+                owner: caller,
+                subaccount: from_subaccount,
+            };
+            let revoke = || {
+                if memo
+                    .as_ref()
+                    .is_some_and(|m| m.0.len() > Self::max_memo_size())
+                {
+                    return Err(Error::Custom("memo size too large").into());
+                }
+                if let Some(ct) = created_at {
+                    Self::check_created_at(ct, now)?;
+                }
+                match Self::holder_of(token_id) {
+                    Some(holder) if holder == from => {}
+                    Some(_) => return Err(RevokeTokenApprovalError::Unauthorized),
+                    None => return Err(RevokeTokenApprovalError::NonExistingTokenId),
+                }
+                Self::get_token_approvals().with(|k| {
+                    let mut approvals = k.borrow_mut();
+                    let keys: Vec<_> = token_approval_keys(&approvals, token_id)
+                        .filter(|(_, s, f)| f.0 == from && spender.is_none_or(|sp| sp == s.0))
+                        .collect();
+                    if keys.is_empty() {
+                        return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+                    }
+                    for key in keys {
+                        approvals.remove(&key);
+                    }
+                    Ok(())
+                })
+            };
+            res.push(revoke().map(|_| {
+                Self::add_transaction(Transaction {
+                    ts: now,
+                    token_id: Some(token_id),
+                    op: "37revoke".to_string(),
+                    from: Some(from),
+                    to: None,
+                    meta: None,
+                    memo,
+                    spender,
+                    exp: None,
+                    phash: None,
+                    version: SCHEMA_VERSION,
+                })
+            }));
+            res
+        }))
+    }
+    fn revoke_collection_approvals(
+        args: Vec<(
+            Option<Subaccount>, /*from_subaccount*/
+            Option<Account>,    /*spender*/
+            Option<Memo>,       /*memo*/
+            Option<u64>,        /*created_at*/
+        )>,
+    ) -> std::result::Result<
+        Vec<std::result::Result<u64, RevokeCollectionApprovalError>>,
+        RevokeCollectionApprovalError,
+    > {
+        if args.is_empty() {
+            return Err(RevokeCollectionApprovalError::batch(Error::Custom(
+                "no revoke args provided",
+            )));
+        }
+        if args.len() > Self::max_revoke_approvals() {
+            return Err(RevokeCollectionApprovalError::batch(Error::Custom(
+                "exceeds max revoke approvals",
+            )));
+        }
+        Self::tip_hash().map_err(RevokeCollectionApprovalError::batch)?;
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        Ok(args.into_iter().fold(vec![], |mut res, arg| {
+            let (from_subaccount, spender, memo, created_at) = arg;
+            let from = Account {
+                owner: caller,
+                subaccount: from_subaccount,
+            };
+            let revoke = || {
+                if memo
+                    .as_ref()
+                    .is_some_and(|m| m.0.len() > Self::max_memo_size())
+                {
+                    return Err(Error::Custom("memo size too large").into());
+                }
+                if let Some(ct) = created_at {
+                    Self::check_created_at(ct, now)?;
+                }
+                Self::get_collection_approvals().with(|k| {
+                    let mut approvals = k.borrow_mut();
+                    let keys: Vec<_> = collection_approval_keys(&approvals, from)
+                        .filter(|(_, s)| spender.is_none_or(|sp| sp == s.0))
+                        .collect();
+                    if keys.is_empty() {
+                        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+                    }
+                    for key in keys {
+                        approvals.remove(&key);
+                    }
+                    Ok(())
+                })
+            };
+            res.push(revoke().map(|_| {
+                Self::add_transaction(Transaction {
+                    ts: now,
+                    token_id: None,
+                    op: "37revoke_coll".to_string(),
+                    from: Some(from),
+                    to: None,
+                    meta: None,
+                    memo,
+                    spender,
+                    exp: None,
+                    phash: None,
+                    version: SCHEMA_VERSION,
+                })
+            }));
+            res
+        }))
+    }
+    fn is_approved(
+        args: Vec<(
+            Account,            /*spender*/
+            Option<Subaccount>, /*from_subaccount*/
+            u64,                /*token_id*/
+        )>,
+    ) -> Result<Vec<bool>> {
+        if args.len() > Self::max_query_batch_size() {
+            return Err(Error::Custom("exceeds max query batch size"));
+        }
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        Ok(args
+            .into_iter()
+            .map(|(spender, from_subaccount, token_id)| {
+                let from = Account {
+                    owner: caller,
+                    subaccount: from_subaccount,
+                };
+                Self::holder_of(token_id) == Some(from)
+                    && Self::has_approval(from, spender, token_id, now)
+            })
+            .collect())
+    }
+    /// Whether `from` has an active token or collection level approval for
+    /// `spender` covering `token_id`.
+    fn has_approval(from: Account, spender: Account, token_id: u64, now: u64) -> bool {
+        Self::get_token_approvals()
+            .with(|k| k.borrow().get(&(token_id, spender.into(), from.into())))
+            .is_some_and(|a| a.is_active(now))
+            || Self::get_collection_approvals()
+                .with(|k| k.borrow().get(&(from.into(), spender.into())))
+                .is_some_and(|a| a.is_active(now))
+    }
+    fn token_approvals(
+        token_id: u64,
+        prev: Option<Account>, /*spender*/
+        take: Option<usize>,
+    ) -> Result<Vec<(Account /*spender*/, Account /*from*/, Approval)>> {
+        let take = take.unwrap_or(Self::default_take_value());
+        if take > Self::max_take_value() {
+            return Err(Error::Custom("exceeds max take value"));
+        }
+        Self::get_token_approvals().with(|k| {
+            let approvals = k.borrow();
+            let start = prev.map_or(AccountKey::MIN, AccountKey);
+            let mut iter = approvals
+                .range((token_id, start, AccountKey::MIN)..)
+                .take_while(|((id, _, _), _)| *id == token_id)
+                .peekable();
+            if let Some(prev) = prev {
+                while iter.next_if(|((_, s, _), _)| s.0 == prev).is_some() {}
+            }
+            Ok(iter
+                .take(take)
+                .map(|((_, spender, from), approval)| (spender.0, from.0, approval))
+                .collect())
+        })
+    }
+    fn collection_approvals(
+        owner: Account,
+        prev: Option<Account>, /*spender*/
+        take: Option<usize>,
+    ) -> Result<Vec<(Account /*spender*/, Approval)>> {
+        let take = take.unwrap_or(Self::default_take_value());
+        if take > Self::max_take_value() {
+            return Err(Error::Custom("exceeds max take value"));
+        }
+        Self::get_collection_approvals().with(|k| {
+            let approvals = k.borrow();
+            let start = prev.map_or(AccountKey::MIN, AccountKey);
+            let mut iter = approvals
+                .range((AccountKey(owner), start)..)
+                .take_while(|((f, _), _)| f.0 == owner)
+                .peekable();
+            if let Some(prev) = prev {
+                iter.next_if(|((_, s), _)| s.0 == prev);
+            }
+            Ok(iter
+                .take(take)
+                .map(|((_, spender), approval)| (spender.0, approval))
+                .collect())
+        })
+    }
+    fn transfer_from(
+        args: Vec<(
+            Option<Subaccount>, /*spender_subaccount*/
+            Account,            /*from*/
+            Account,            /*to*/
+            u64,                /*token_id*/
+            Option<Memo>,       /*memo*/
+            Option<u64>,        /*created_at*/
+        )>,
+    ) -> std::result::Result<Vec<std::result::Result<u64, TransferError>>, TransferError> {
+        if args.is_empty() {
+            return Err(TransferError::batch(Error::Custom(
+                "no transfer args provided",
+            )));
+        }
+        if args.len() > Self::max_update_batch_size() {
+            return Err(TransferError::batch(Error::Custom(
+                "exceeds max update batch size",
+            )));
+        }
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        let res: Vec<std::result::Result<Option<(u64, [u8; 32])>, TransferError>> = args
+            .iter()
+            .map(|arg| {
+                let (spender_subaccount, from, to, token_id, memo, created_at) = arg;
+                let spender = Account {
+                    owner: caller,
+                    subaccount: *spender_subaccount,
+                };
+                if to.owner == Principal::anonymous() || to == from {
+                    return Err(TransferError::InvalidRecipient);
+                }
+                if memo
+                    .as_ref()
+                    .is_some_and(|m| m.0.len() > Self::max_memo_size())
+                {
+                    return Err(Error::Custom("memo size too large").into());
+                }
+                if let Some(ct) = *created_at {
+                    Self::check_created_at(ct, now)?;
+                }
+                let dedup = created_at.map(|ct| (ct, request_hash(caller, "37xfr", arg)));
+                if let Some(duplicate_of) = dedup.and_then(|(ct, h)| Self::find_duplicate(ct, h)) {
+                    return Err(TransferError::Duplicate {
+                        duplicate_of: duplicate_of.into(),
+                    });
+                }
+                match Self::holder_of(*token_id) {
+                    Some(holder)
+                        if holder == *from
+                            && Self::has_approval(*from, spender, *token_id, now) =>
+                    {
+                        Ok(dedup)
+                    }
+                    Some(_) => Err(TransferError::Unauthorized),
+                    None => Err(TransferError::NonExistingTokenId),
+                }
+            })
+            .collect();
+        if Self::atomic_batch_transfers() && args.len() > 1 && res.iter().any(|r| r.is_err()) {
+            return Err(TransferError::batch(Error::Custom("invalid transfer args")));
+        }
+        Self::tip_hash().map_err(TransferError::batch)?;
+        Ok(args
+            .into_iter()
+            .zip(res)
+            .map(
+                |((spender_subaccount, from, to, token_id, memo, _created_at), res)| {
+                    let dedup = res?;
+                    if let Some(duplicate_of) =
+                        dedup.and_then(|(ct, h)| Self::find_duplicate(ct, h))
+                    {
+                        return Err(TransferError::Duplicate {
+                            duplicate_of: duplicate_of.into(),
+                        });
+                    }
+                    // an earlier entry of the batch may have moved the token
+                    if Self::holder_of(token_id) != Some(from) {
+                        return Err(TransferError::Unauthorized);
+                    }
+                    Self::set_holder(token_id, Some(to));
+                    clear_token_approvals::<Self>(token_id, from);
+                    let index = Self::add_transaction(Transaction {
+                        ts: now,
+                        token_id: Some(token_id),
+                        op: "37xfr".to_string(),
+                        from: Some(from),
+                        to: Some(to),
+                        meta: None,
+                        memo,
+                        spender: Some(Account {
+                            owner: caller,
+                            subaccount: spender_subaccount,
+                        }),
+                        exp: None,
+                        phash: None,
+                        version: SCHEMA_VERSION,
+                    });
+                    if let Some((ct, h)) = dedup {
+                        Self::record_request(ct, h, index);
+                    }
+                    Ok(index)
+                },
+            )
+            .collect())
+    }
+}
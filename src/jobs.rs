@@ -0,0 +1,638 @@
+use crate::{
+    split_token_id, token_id, AccountKey, Error, Icrc7, Memory, Metadata, MintError, Result,
+    Stored, SupplyDrift, TokenInner,
+};
+use candid::{CandidType, Principal};
+use ciborium::{from_reader, into_writer};
+use ic_cdk::api::call::{CallResult, RejectionCode};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::hash::Hash;
+use std::thread::LocalKey;
+use std::time::Duration;
+
+/// Entries a step of any job clears, writes, moves or counts.
+const ENTRY_STEP: usize = 100;
+/// Chunks in a row that may trap before their job fails.
+const MAX_ATTEMPTS: u32 = 3;
+/// Drifted counters a recount job reports, the first ones it finds.
+const MAX_DRIFT: usize = 100;
+
+thread_local! {
+    /// Interval timer starting chunks while any job is active. The timer
+    /// runtime rearms it even when a chunk traps, so a trap can not leave
+    /// active jobs without a pending timer.
+    static TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+    /// Whether a chunk is running in its own message.
+    static CHUNK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Work a job still has to do, kept in stable memory between chunks.
+#[derive(Clone, Deserialize, Serialize)]
+pub enum JobWork {
+    /// Mints one edition of `definition_id` to each holder from `next` on, a
+    /// batch of `max_update_batch_size` per step.
+    Mint {
+        definition_id: u64,
+        holders: Vec<Account>,
+        memo: Option<Memo>,
+        next: usize,
+    },
+    /// Clears the owner index, then refills it from the holder entries after
+    /// `cursor`.
+    RebuildOwnerIndex {
+        cleared: bool,
+        cursor: Option<(u64, Account)>,
+    },
+    /// Brings the definitions after `cursor` up to the current layout, see
+    /// [`Icrc7::migrate_definition`].
+    MigrateDefinitions {
+        cursor: Option<u64>,
+        index_assets: bool,
+    },
+    /// Counts the live editions of `definition` from the one after `after`
+    /// on, the definitions before it are recounted. Counting a definition
+    /// starts over when a mint or burn moves its stored count away from
+    /// `stored`, so the count it ends with is exact.
+    RecountSupply {
+        definition: Option<u64>,
+        after: Option<u64>,
+        counted: u64,
+        stored: u64,
+        drift: Vec<SupplyDrift>,
+    },
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed { message: String },
+}
+
+impl JobState {
+    fn is_active(&self) -> bool {
+        matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+impl JobWork {
+    /// Drops what a finished job no longer needs, the holders of a mint can
+    /// be large.
+    fn compact(&mut self) {
+        if let JobWork::Mint { holders, .. } = self {
+            *holders = vec![];
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Job {
+    pub id: u64,
+    /// Principal that submitted the job, mints run on its behalf.
+    pub caller: Principal,
+    pub work: JobWork,
+    pub state: JobState,
+    pub processed: u64,
+    pub total: u64,
+    /// Items that failed without stopping the job, such as an invalid
+    /// recipient of a mint.
+    pub failed: u64,
+    pub last_error: Option<String>,
+    /// Chunks started since one last finished, a chunk that traps leaves it
+    /// raised.
+    pub attempts: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for Job {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Collection data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Collection data")
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum JobKind {
+    Mint {
+        definition_id: u64,
+    },
+    RebuildOwnerIndex,
+    MigrateDefinitions,
+    /// Drifted counters found so far, see [`MAX_DRIFT`].
+    RecountSupply {
+        drift: Vec<SupplyDrift>,
+    },
+}
+
+/// Progress of a job as reported by `job_status`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct JobStatus {
+    pub id: u64,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub processed: u64,
+    pub total: u64,
+    pub failed: u64,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl From<Job> for JobStatus {
+    fn from(job: Job) -> Self {
+        JobStatus {
+            id: job.id,
+            kind: match job.work {
+                JobWork::Mint { definition_id, .. } => JobKind::Mint { definition_id },
+                JobWork::RebuildOwnerIndex { .. } => JobKind::RebuildOwnerIndex,
+                JobWork::MigrateDefinitions { .. } => JobKind::MigrateDefinitions,
+                JobWork::RecountSupply { drift, .. } => JobKind::RecountSupply { drift },
+            },
+            state: job.state,
+            processed: job.processed,
+            total: job.total,
+            failed: job.failed,
+            last_error: job.last_error,
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+        }
+    }
+}
+
+pub type Jobs = StableBTreeMap<u64, Job, Memory>;
+/// Ids of queued and running jobs, so finding the next one does not walk the
+/// job history.
+pub type ActiveJobs = StableBTreeMap<u64, (), Memory>;
+
+pub trait Icrc7JobStorage {
+    fn get_jobs() -> &'static LocalKey<RefCell<Jobs>>;
+    fn get_active_jobs() -> &'static LocalKey<RefCell<ActiveJobs>>;
+    /// Stores the job and keeps the active index in step with its state.
+    fn save_job(mut job: Job) {
+        if job.state.is_active() {
+            Self::get_active_jobs().with(|k| k.borrow_mut().insert(job.id, ()));
+        } else {
+            job.work.compact();
+            Self::get_active_jobs().with(|k| k.borrow_mut().remove(&job.id));
+        }
+        Self::get_jobs().with(|k| k.borrow_mut().insert(job.id, job));
+    }
+}
+
+/// Operations too large for one message, run in chunks from timers. Every
+/// chunk stops once it has spent [`Icrc7Jobs::job_instruction_budget`] and
+/// saves its progress, so jobs survive upgrades and resume in `post_upgrade`.
+pub trait Icrc7Jobs<T>: Icrc7<T>
+where
+    T: Hash + Metadata + DeserializeOwned + Serialize + 'static,
+{
+    /// Instructions a chunk may spend, well below the per-message limit.
+    fn job_instruction_budget() -> u64 {
+        2_000_000_000
+    }
+    /// Queues a mint of one edition of `definition_id` per holder, checked
+    /// up front like [`Icrc7::mint`] except for the batch size.
+    fn mint_job(
+        definition_id: u64,
+        holders: Vec<Account>,
+        memo: Option<Memo>,
+    ) -> std::result::Result<u64, MintError>
+    where
+        Self: 'static,
+    {
+        let caller = ic_cdk::caller();
+        if holders.is_empty() {
+            return Err(MintError::batch(Error::Custom("no mint holders provided")));
+        }
+        if memo
+            .as_ref()
+            .is_some_and(|m| m.0.len() > Self::max_memo_size())
+        {
+            return Err(MintError::batch(Error::Custom("memo size too large")));
+        }
+        let token = match Self::get_token(definition_id) {
+            Ok(Some(t)) => t,
+            Ok(None) => return Err(MintError::NonExistingTokenId),
+            Err(e) => return Err(MintError::batch(e.into())),
+        };
+        if token.creator.owner != caller {
+            return Err(MintError::Unauthorized);
+        }
+        let minted = token.minted as usize + holders.len();
+        if token.supply_cap.is_some_and(|cap| minted > cap) || minted > u32::MAX as usize {
+            return Err(MintError::SupplyCapReached);
+        }
+        let total = holders.len() as u64;
+        Ok(Self::submit_job(
+            caller,
+            JobWork::Mint {
+                definition_id,
+                holders,
+                memo,
+                next: 0,
+            },
+            total,
+        ))
+    }
+    /// Queues a rebuild of the owner index, controllers only. Balances read
+    /// from the index are incomplete until the job is done.
+    fn reindex_job() -> Result<u64>
+    where
+        Self: 'static,
+    {
+        let caller = ic_cdk::caller();
+        if !ic_cdk::api::is_controller(&caller) {
+            return Err(Error::Custom("caller is not a controller"));
+        }
+        let total = Self::get_holders().with(|k| k.borrow().len());
+        Ok(Self::submit_job(
+            caller,
+            JobWork::RebuildOwnerIndex {
+                cleared: false,
+                cursor: None,
+            },
+            total,
+        ))
+    }
+    /// Queues a recount of the supply counters from the holder entries,
+    /// controllers only. The job status lists the counters that drifted.
+    fn recount_job() -> Result<u64>
+    where
+        Self: 'static,
+    {
+        let caller = ic_cdk::caller();
+        if !ic_cdk::api::is_controller(&caller) {
+            return Err(Error::Custom("caller is not a controller"));
+        }
+        let total = Self::get_tokens().with(|k| k.borrow().len());
+        Ok(Self::submit_job(caller, Self::recount_work(), total))
+    }
+    /// Queues the upgrade work too large for `post_upgrade` itself: bringing
+    /// definitions of older layouts up to date, then rebuilding the owner
+    /// index and recounting supply when they are missing. Work still queued
+    /// by an earlier upgrade is not queued twice. Until the jobs are done,
+    /// holders kept inside old definitions are not visible and balances and
+    /// supply may be incomplete.
+    fn queue_migrations()
+    where
+        Self: 'static,
+    {
+        let definitions = Self::get_tokens().with(|k| k.borrow().len());
+        if definitions == 0 {
+            return;
+        }
+        let canister = ic_cdk::api::id();
+        if !Self::job_queued(|w| matches!(w, JobWork::MigrateDefinitions { .. })) {
+            let index_assets = Self::get_assets().with(|k| k.borrow().is_empty());
+            Self::submit_job(
+                canister,
+                JobWork::MigrateDefinitions {
+                    cursor: None,
+                    index_assets,
+                },
+                definitions,
+            );
+        }
+        let unindexed = Self::get_owner_index().with(|k| k.borrow().is_empty());
+        if unindexed && !Self::job_queued(|w| matches!(w, JobWork::RebuildOwnerIndex { .. })) {
+            Self::submit_job(
+                canister,
+                JobWork::RebuildOwnerIndex {
+                    cleared: false,
+                    cursor: None,
+                },
+                Self::get_holders().with(|k| k.borrow().len()),
+            );
+        }
+        if unindexed && !Self::job_queued(|w| matches!(w, JobWork::RecountSupply { .. })) {
+            Self::submit_job(canister, Self::recount_work(), definitions);
+        }
+    }
+    /// Whether an active job's work matches `work`.
+    fn job_queued(work: impl Fn(&JobWork) -> bool) -> bool {
+        let ids: Vec<u64> =
+            Self::get_active_jobs().with(|k| k.borrow().iter().map(|(id, _)| id).collect());
+        ids.into_iter()
+            .filter_map(|id| Self::get_jobs().with(|k| k.borrow().get(&id)))
+            .any(|job| work(&job.work))
+    }
+    fn recount_work() -> JobWork {
+        JobWork::RecountSupply {
+            definition: Self::next_definition(None).map(|(id, _)| id),
+            after: None,
+            counted: 0,
+            stored: 0,
+            drift: vec![],
+        }
+    }
+    fn submit_job(caller: Principal, work: JobWork, total: u64) -> u64
+    where
+        Self: 'static,
+    {
+        let now = ic_cdk::api::time();
+        let id = Self::get_jobs().with(|k| k.borrow().last_key_value().map_or(1, |(id, _)| id + 1));
+        Self::save_job(Job {
+            id,
+            caller,
+            work,
+            state: JobState::Queued,
+            processed: 0,
+            total,
+            failed: 0,
+            last_error: None,
+            attempts: 0,
+            created_at: now,
+            updated_at: now,
+        });
+        Self::schedule_jobs();
+        id
+    }
+    fn job_status(id: u64) -> Option<JobStatus> {
+        Self::get_jobs().with(|k| k.borrow().get(&id).map(JobStatus::from))
+    }
+    /// Stops a queued or running job, work already done stays done. Only the
+    /// submitter and controllers may cancel.
+    fn cancel_job(id: u64) -> Result<()> {
+        let caller = ic_cdk::caller();
+        let mut job = match Self::get_jobs().with(|k| k.borrow().get(&id)) {
+            Some(job) => job,
+            None => return Err(Error::Custom("job not found")),
+        };
+        if job.caller != caller && !ic_cdk::api::is_controller(&caller) {
+            return Err(Error::Custom("caller is not the job owner"));
+        }
+        if !job.state.is_active() {
+            return Err(Error::Custom("job is not running"));
+        }
+        job.state = JobState::Cancelled;
+        job.updated_at = ic_cdk::api::time();
+        Self::save_job(job);
+        Ok(())
+    }
+    /// Oldest active job.
+    fn next_job() -> Option<Job> {
+        let id = Self::get_active_jobs()
+            .with(|k| k.borrow().first_key_value())?
+            .0;
+        Self::get_jobs().with(|k| k.borrow().get(&id))
+    }
+    /// Starts the job timer unless it is running, timers do not survive
+    /// upgrades so `post_upgrade` calls this again.
+    fn schedule_jobs()
+    where
+        Self: 'static,
+    {
+        if TIMER.get().is_none() && Self::next_job().is_some() {
+            let timer = ic_cdk_timers::set_timer_interval(Duration::ZERO, Self::run_jobs);
+            TIMER.set(Some(timer));
+        }
+    }
+    /// Starts a chunk of the oldest active job, stops the timer once no job
+    /// is left. The attempt is saved before the chunk runs in a call of the
+    /// canister to itself, so a chunk that traps is still counted and its job
+    /// fails after [`MAX_ATTEMPTS`] in a row instead of repeating forever.
+    fn run_jobs()
+    where
+        Self: 'static,
+    {
+        if CHUNK.get() {
+            return;
+        }
+        let Some(mut job) = Self::next_job() else {
+            if let Some(timer) = TIMER.take() {
+                ic_cdk_timers::clear_timer(timer);
+            }
+            return;
+        };
+        job.updated_at = ic_cdk::api::time();
+        if job.attempts >= MAX_ATTEMPTS {
+            job.state = JobState::Failed {
+                message: format!("{} chunks in a row trapped", job.attempts),
+            };
+            Self::save_job(job);
+            return;
+        }
+        job.attempts += 1;
+        job.state = JobState::Running;
+        let id = job.id;
+        Self::save_job(job);
+        CHUNK.set(true);
+        ic_cdk::spawn(async move {
+            let result: CallResult<()> =
+                ic_cdk::call(ic_cdk::api::id(), "run_job_chunk", (id,)).await;
+            CHUNK.set(false);
+            if let Err((code, message)) = result {
+                Self::chunk_rejected(id, code, message);
+            }
+        });
+    }
+    /// Runs job `id` until it is done or the chunk is out of budget, called
+    /// by [`Icrc7Jobs::run_jobs`] in a message of its own.
+    fn run_job_chunk(id: u64) {
+        let Some(mut job) = Self::get_jobs().with(|k| k.borrow().get(&id)) else {
+            return;
+        };
+        if !job.state.is_active() {
+            return;
+        }
+        while ic_cdk::api::instruction_counter() < Self::job_instruction_budget() {
+            match Self::job_step(&mut job) {
+                Ok(true) => {}
+                Ok(false) => {
+                    job.state = JobState::Done;
+                    break;
+                }
+                Err(message) => {
+                    job.state = JobState::Failed { message };
+                    break;
+                }
+            }
+        }
+        job.attempts = 0;
+        job.updated_at = ic_cdk::api::time();
+        Self::save_job(job);
+    }
+    /// Records why a chunk did not finish. An attempt that never ran, such
+    /// as a call the system did not deliver, is not counted.
+    fn chunk_rejected(id: u64, code: RejectionCode, message: String) {
+        let Some(mut job) = Self::get_jobs().with(|k| k.borrow().get(&id)) else {
+            return;
+        };
+        if !job.state.is_active() {
+            return;
+        }
+        if code == RejectionCode::CanisterError {
+            job.last_error = Some(format!("chunk trapped: {message}"));
+        } else {
+            job.attempts = job.attempts.saturating_sub(1);
+        }
+        Self::save_job(job);
+    }
+    /// Does one unit of work, returns whether any is left.
+    fn job_step(job: &mut Job) -> std::result::Result<bool, String> {
+        match &mut job.work {
+            JobWork::Mint {
+                definition_id,
+                holders,
+                memo,
+                next,
+            } => {
+                let end = holders
+                    .len()
+                    .min(*next + Self::max_update_batch_size().max(1));
+                if *next >= end {
+                    return Ok(false);
+                }
+                let results = Self::mint_by(
+                    job.caller,
+                    *definition_id,
+                    holders[*next..end].to_vec(),
+                    memo.clone(),
+                    None,
+                )
+                .map_err(|e| e.to_string())?;
+                for e in results.into_iter().filter_map(|r| r.err()) {
+                    job.failed += 1;
+                    job.last_error = Some(e.to_string());
+                }
+                job.processed += (end - *next) as u64;
+                *next = end;
+                Ok(*next < holders.len())
+            }
+            JobWork::RebuildOwnerIndex { cleared, cursor } => {
+                if !*cleared {
+                    Self::get_owner_index().with(|k| {
+                        let mut index = k.borrow_mut();
+                        let keys: Vec<_> =
+                            index.iter().take(ENTRY_STEP).map(|(key, _)| key).collect();
+                        *cleared = keys.is_empty();
+                        for key in keys {
+                            index.remove(&key);
+                        }
+                    });
+                    if *cleared {
+                        // holders may have moved in since the job was queued
+                        job.total = Self::get_holders().with(|k| k.borrow().len());
+                    }
+                    return Ok(true);
+                }
+                let start = match cursor {
+                    Some((token_id, holder)) => {
+                        std::ops::Bound::Excluded((*token_id, AccountKey(*holder)))
+                    }
+                    None => std::ops::Bound::Unbounded,
+                };
+                let entries: Vec<(u64, AccountKey)> = Self::get_holders().with(|k| {
+                    k.borrow()
+                        .range((start, std::ops::Bound::Unbounded))
+                        .take(ENTRY_STEP)
+                        .map(|(key, _)| key)
+                        .collect()
+                });
+                Self::get_owner_index().with(|k| {
+                    let mut index = k.borrow_mut();
+                    for (token_id, holder) in &entries {
+                        index.insert((*holder, *token_id), ());
+                    }
+                });
+                job.processed += entries.len() as u64;
+                match entries.last() {
+                    Some((token_id, holder)) => {
+                        *cursor = Some((*token_id, holder.0));
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+            JobWork::MigrateDefinitions {
+                cursor,
+                index_assets,
+            } => {
+                let Some((definition_id, token)) = Self::next_definition(*cursor) else {
+                    return Ok(false);
+                };
+                match Self::migrate_definition(definition_id, token, *index_assets, ENTRY_STEP) {
+                    Ok(true) => return Ok(true),
+                    Ok(false) => {}
+                    Err(e) => {
+                        job.failed += 1;
+                        job.last_error = Some(format!("definition {definition_id}: {e}"));
+                    }
+                }
+                *cursor = Some(definition_id);
+                job.processed += 1;
+                Ok(true)
+            }
+            JobWork::RecountSupply {
+                definition,
+                after,
+                counted,
+                stored,
+                drift,
+            } => {
+                let Some(definition_id) = *definition else {
+                    return Ok(false);
+                };
+                let current = Self::definition_supply(definition_id) as u64;
+                if after.is_none() || current != *stored {
+                    *after = None;
+                    *counted = 0;
+                    *stored = current;
+                }
+                let start = after.map_or(token_id(definition_id, 0), |id| id + 1);
+                let ids: Vec<u64> = Self::get_holders().with(|k| {
+                    k.borrow()
+                        .range((start, AccountKey::MIN)..)
+                        .map(|((id, _), _)| id)
+                        .take_while(|id| split_token_id(*id).0 == definition_id)
+                        .take(ENTRY_STEP)
+                        .collect()
+                });
+                *counted += ids.len() as u64;
+                if ids.len() == ENTRY_STEP {
+                    *after = ids.last().copied();
+                    return Ok(true);
+                }
+                let stored = Self::set_definition_supply(definition_id, *counted)
+                    .map_err(|e| e.to_string())?;
+                if stored != *counted && drift.len() < MAX_DRIFT {
+                    drift.push(SupplyDrift {
+                        definition_id,
+                        stored,
+                        actual: *counted,
+                    });
+                }
+                *definition = Self::next_definition(Some(definition_id)).map(|(id, _)| id);
+                *after = None;
+                job.processed += 1;
+                Ok(definition.is_some())
+            }
+        }
+    }
+    /// First definition after `cursor`.
+    fn next_definition(cursor: Option<u64>) -> Option<(u64, Stored<TokenInner<T>>)> {
+        let start = match cursor {
+            Some(id) => std::ops::Bound::Excluded(id),
+            None => std::ops::Bound::Unbounded,
+        };
+        Self::get_tokens().with(|k| k.borrow().range((start, std::ops::Bound::Unbounded)).next())
+    }
+}
@@ -1,21 +1,38 @@
-use candid::Principal;
+use candid::{CandidType, Nat, Principal};
 use ciborium::{from_reader, into_writer};
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::Storable;
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use icrc_ledger_types::icrc1::transfer::Memo;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sha3::Sha3_256;
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::BTreeMap;
 use std::hash::Hash;
 use std::thread::LocalKey;
+use std::time::Duration;
+
+mod icrc3;
+mod icrc37;
+mod jobs;
+mod schema;
+use icrc3::certify_tip;
+pub use icrc3::*;
+pub use icrc37::*;
+pub use jobs::*;
+pub use schema::{Stored, SCHEMA_VERSION};
 
 pub use candid;
 pub use ciborium;
 pub use ic_cdk;
+pub use ic_cdk_timers;
 pub use ic_stable_structures;
 pub use icrc_ledger_types;
 pub use num_traits;
+pub use serde_bytes;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -23,17 +40,265 @@ pub enum Error {
     Custom(&'static str),
     #[error("{0}")]
     Struct(#[from] ic_stable_structures::GrowFailed),
+    #[error("{0}")]
+    Decode(#[from] DecodeError),
+}
+
+impl Error {
+    pub fn code(&self) -> u64 {
+        match self {
+            Error::Custom(_) => 1,
+            Error::Struct(_) => 2,
+            Error::Decode(_) => 3,
+        }
+    }
 }
 
+/// Stored bytes that no known layout of the record decodes.
+#[derive(thiserror::Error, CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[error("failed to decode stored record: {0}")]
+pub struct DecodeError(pub String);
+
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// `batch` for errors that fail a whole call, `From<Error>` for errors of a
+/// single entry and `From<TimeError>` for the `created_at_time` checks of a
+/// typed error enum with the matching variants.
+macro_rules! batch_error {
+    ($error:ident) => {
+        impl $error {
+            pub fn batch(e: $crate::Error) -> Self {
+                $error::GenericBatchError {
+                    error_code: e.code().into(),
+                    message: e.to_string(),
+                }
+            }
+        }
+
+        impl From<$crate::Error> for $error {
+            fn from(e: $crate::Error) -> Self {
+                $error::GenericError {
+                    error_code: e.code().into(),
+                    message: e.to_string(),
+                }
+            }
+        }
+
+        impl From<$crate::TimeError> for $error {
+            fn from(e: $crate::TimeError) -> Self {
+                match e {
+                    $crate::TimeError::TooOld => $error::TooOld,
+                    $crate::TimeError::CreatedInFuture { ledger_time } => {
+                        $error::CreatedInFuture { ledger_time }
+                    }
+                }
+            }
+        }
+    };
+}
+pub(crate) use batch_error;
+
+#[derive(thiserror::Error, CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum TransferError {
+    #[error("non existing token id")]
+    NonExistingTokenId,
+    #[error("invalid recipient")]
+    InvalidRecipient,
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("too old")]
+    TooOld,
+    #[error("created in future, ledger time {ledger_time}")]
+    CreatedInFuture { ledger_time: u64 },
+    #[error("duplicate of {duplicate_of}")]
+    Duplicate { duplicate_of: Nat },
+    #[error("{message}")]
+    GenericError { error_code: Nat, message: String },
+    #[error("{message}")]
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+batch_error!(TransferError);
+
+/// Outcome of the ICRC-7 `created_at_time` checks, see [`Icrc7::check_created_at`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+}
+
+/// ICRC-7 `created_at_time` check, `created_at` and `now` in nanoseconds,
+/// `tx_window` and `permitted_drift` in seconds.
+fn check_created_at(
+    created_at: u64,
+    now: u64,
+    tx_window: u64,
+    permitted_drift: u64,
+) -> std::result::Result<(), TimeError> {
+    let window = tx_window.saturating_mul(1_000_000_000);
+    let drift = permitted_drift.saturating_mul(1_000_000_000);
+    if created_at < now.saturating_sub(window.saturating_add(drift)) {
+        Err(TimeError::TooOld)
+    } else if created_at > now.saturating_add(drift) {
+        Err(TimeError::CreatedInFuture { ledger_time: now })
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum MintError {
+    #[error("non existing token id")]
+    NonExistingTokenId,
+    #[error("invalid recipient")]
+    InvalidRecipient,
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("supply cap reached")]
+    SupplyCapReached,
+    #[error("too old")]
+    TooOld,
+    #[error("created in future, ledger time {ledger_time}")]
+    CreatedInFuture { ledger_time: u64 },
+    #[error("duplicate of {duplicate_of}")]
+    Duplicate { duplicate_of: Nat },
+    #[error("{message}")]
+    GenericError { error_code: Nat, message: String },
+    #[error("{message}")]
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+batch_error!(MintError);
+
+#[derive(thiserror::Error, CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum BurnError {
+    #[error("non existing token id")]
+    NonExistingTokenId,
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("too old")]
+    TooOld,
+    #[error("created in future, ledger time {ledger_time}")]
+    CreatedInFuture { ledger_time: u64 },
+    #[error("duplicate of {duplicate_of}")]
+    Duplicate { duplicate_of: Nat },
+    #[error("{message}")]
+    GenericError { error_code: Nat, message: String },
+    #[error("{message}")]
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+batch_error!(BurnError);
+
+pub type Memory =
+    ic_stable_structures::memory_manager::VirtualMemory<ic_stable_structures::DefaultMemoryImpl>;
+
+/// Fixed size encoding of an [`Account`] for use inside stable map keys,
+/// `Account` itself is only `Storable` as an unbounded CBOR blob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AccountKey(pub Account);
+
+impl AccountKey {
+    /// Sorts before every other account.
+    pub const MIN: AccountKey = AccountKey(Account {
+        owner: Principal::management_canister(),
+        subaccount: None,
+    });
+}
+
+impl From<Account> for AccountKey {
+    fn from(account: Account) -> Self {
+        AccountKey(account)
+    }
+}
+
+impl Storable for AccountKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 62,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let owner = self.0.owner.as_slice();
+        let mut buf = vec![0; 62];
+        buf[0] = owner.len() as u8;
+        buf[1..1 + owner.len()].copy_from_slice(owner);
+        buf[30..].copy_from_slice(self.0.effective_subaccount());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let len = bytes[0] as usize;
+        let subaccount: Subaccount = bytes[30..62].try_into().expect("invalid account key");
+        AccountKey(Account {
+            owner: Principal::from_slice(&bytes[1..1 + len]),
+            subaccount: (subaccount != [0; 32]).then_some(subaccount),
+        })
+    }
+}
+
+/// ICRC-3/ICRC-16 generic value used for token and collection metadata.
+pub type Value = ICRC3Value;
+
+/// Token metadata as returned by `icrc7_token_metadata`.
+pub type TokenMetadata = Vec<(String, Value)>;
+
 pub trait Metadata {
+    fn metadata(&self) -> Vec<(String, Value)>;
+}
+
+/// Token types that describe themselves with a JSON object. Every such type
+/// gets a [`Metadata`] impl that converts the object into a `Value` map.
+pub trait JsonMetadata {
     fn metadata(&self) -> String;
 }
 
-//pub trait Icrc10Trait {
-//    fn supported_standards(&self) -> Result<Vec<Standard>>;
-//}
+impl<T: JsonMetadata> Metadata for T {
+    fn metadata(&self) -> Vec<(String, Value)> {
+        match serde_json::from_str(&JsonMetadata::metadata(self)) {
+            Ok(serde_json::Value::Object(entries)) => entries
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, json_to_value(v)?)))
+                .collect(),
+            Ok(v) => json_to_value(v)
+                .map(|v| vec![("icrc7:metadata".to_string(), v)])
+                .unwrap_or_default(),
+            Err(_) => vec![],
+        }
+    }
+}
+
+/// Converts JSON into a `Value`, `null` has no counterpart and yields `None`.
+pub fn json_to_value(json: serde_json::Value) -> Option<Value> {
+    use serde_json::Value as Json;
+    Some(match json {
+        Json::Null => return None,
+        Json::Bool(b) => Value::Text(b.to_string()),
+        Json::Number(n) => match (n.as_u64(), n.as_i64()) {
+            (Some(u), _) => Value::Nat(u.into()),
+            (_, Some(i)) => Value::Int(i.into()),
+            _ => Value::Text(n.to_string()),
+        },
+        Json::String(s) => Value::Text(s),
+        Json::Array(items) => Value::Array(items.into_iter().filter_map(json_to_value).collect()),
+        Json::Object(entries) => Value::Map(
+            entries
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, json_to_value(v)?)))
+                .collect(),
+        ),
+    })
+}
+
+pub const ICRC3_URL: &str =
+    "https://github.com/dfinity/ICRC-1/blob/main/standards/ICRC-3/README.md";
+pub const ICRC7_URL: &str = "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-7/ICRC-7.md";
+pub const ICRC10_URL: &str = "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10/ICRC-10.md";
+pub const ICRC37_URL: &str = "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-37/ICRC-37.md";
+
+pub trait Icrc10 {
+    fn supported_standards() -> Vec<(&'static str /*name*/, &'static str /*url*/)>;
+}
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct TokenInner<T>
@@ -43,53 +308,215 @@ where
     pub id: u64,
     pub token: T,
     pub supply_cap: Option<usize>,
+    /// Nanoseconds since the epoch, as everywhere else in the crate.
     pub created_at: u64,
     pub updated_at: u64,
-    pub owner: Principal,
-    pub holders: HashSet<Principal>,
+    /// Caller of `create_token`, the only one allowed to update and mint it.
+    pub creator: Account,
+    /// Last edition number handed out, burned editions are not reissued.
+    #[serde(default)]
+    pub minted: u32,
+    /// Holders as stored by earlier releases, moved into [`Holders`] by
+    /// [`Icrc7::migrate_definition`] and empty afterwards.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub editions: BTreeMap<u32, Account>,
+    /// [`content_hash`] of `token`, the content fingerprint.
+    #[serde(default)]
+    pub content_hash: Option<AssetHash>,
+    /// [`SCHEMA_VERSION`] of the layout the record was read from.
+    #[serde(default)]
+    pub version: u16,
 }
 
 impl<T> TokenInner<T>
 where
     T: Hash + Metadata,
 {
-    fn total_supply(&self) -> usize {
-        self.holders.len()
+    /// Definition metadata plus the edition number and, for capped
+    /// definitions, the edition size ("3 of 100").
+    pub fn edition_metadata(&self, edition: u32) -> Vec<(String, Value)> {
+        let mut metadata = self.token.metadata();
+        if let Some(hash) = self.content_hash {
+            metadata.push((
+                "content_hash".to_string(),
+                Value::Blob(serde_bytes::ByteBuf::from(hash)),
+            ));
+        }
+        metadata.push(("edition".to_string(), Value::Nat(edition.into())));
+        if let Some(cap) = self.supply_cap {
+            metadata.push(("edition_size".to_string(), Value::Nat(cap.into())));
+        }
+        metadata
     }
 }
 
+/// ICRC-7 token ids are `definition_id << EDITION_BITS | edition`, so every
+/// minted copy of a definition gets its own id. Editions start at 1.
+pub const EDITION_BITS: u32 = 32;
+
+pub fn token_id(definition_id: u64, edition: u32) -> u64 {
+    (definition_id << EDITION_BITS) | edition as u64
+}
+
+pub fn split_token_id(token_id: u64) -> (u64 /*definition_id*/, u32 /*edition*/) {
+    (token_id >> EDITION_BITS, token_id as u32)
+}
+
 impl<T> Storable for TokenInner<T>
 where
     T: Hash + Metadata + Serialize + DeserializeOwned,
 {
     const BOUND: Bound = Bound::Unbounded;
 
-    fn to_bytes(&self) -> Cow<[u8]> {
-        let mut buf = vec![];
-        into_writer(self, &mut buf).expect("failed to encode Collection data");
-        Cow::Owned(buf)
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self, self.version))
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        from_reader(&bytes[..]).expect("failed to decode Collection data")
+        schema::decode_token(&bytes).expect("failed to decode Collection data")
     }
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Transaction {
     pub ts: u64,
-    pub token_id: u64,
+    pub token_id: Option<u64>,
     pub op: String,
-    pub from: Option<Principal>,
-    pub to: Option<Principal>,
-    pub metadata: String,
+    pub from: Option<Account>,
+    pub to: Option<Account>,
     pub memo: Option<Memo>,
+    /// Token metadata at mint time, only set on `7mint` entries.
+    #[serde(default)]
+    pub meta: Option<Value>,
+    #[serde(default)]
+    pub spender: Option<Account>,
+    #[serde(default)]
+    pub exp: Option<u64>,
+    #[serde(default)]
+    pub phash: Option<[u8; 32]>,
+    /// [`SCHEMA_VERSION`] of the layout the entry was read from.
+    #[serde(default)]
+    pub version: u16,
 }
 
 impl Storable for Transaction {
     const BOUND: Bound = Bound::Unbounded;
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::encode(self, self.version))
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        schema::decode_transaction(&bytes).expect("failed to decode Collection data")
+    }
+}
+
+pub type TokenDefinitions<T> =
+    ic_stable_structures::StableBTreeMap<u64, Stored<TokenInner<T>>, Memory>;
+
+pub trait Icrc7TokenStorage<T>: Icrc7QuarantineStorage
+where
+    T: Hash + Metadata + DeserializeOwned + Serialize + 'static,
+{
+    fn get_tokens() -> &'static LocalKey<std::cell::RefCell<TokenDefinitions<T>>>;
+    /// A definition that does not decode is quarantined and reported as an
+    /// error, callers that only read usually treat it as missing.
+    fn get_token(definition_id: u64) -> std::result::Result<Option<TokenInner<T>>, DecodeError> {
+        match Self::get_tokens().with(|k| k.borrow().get(&definition_id)) {
+            None => Ok(None),
+            Some(Stored::Valid(token)) => Ok(Some(token)),
+            Some(Stored::Corrupt { bytes, error }) => {
+                Self::quarantine(RecordStore::Tokens, definition_id, bytes, &error);
+                Err(error)
+            }
+        }
+    }
+}
+
+pub type TransactionLog = ic_stable_structures::StableLog<Stored<Transaction>, Memory, Memory>;
+
+pub trait Icrc7TransactionStorage: Icrc7QuarantineStorage {
+    fn get_transactions() -> &'static LocalKey<std::cell::RefCell<TransactionLog>>;
+    /// Log entry at `index`, a repaired copy for entries that do not decode.
+    /// Entries without one are quarantined and reported as an error.
+    fn get_transaction(index: u64) -> Option<std::result::Result<Transaction, DecodeError>> {
+        match Self::get_transactions().with(|k| k.borrow().get(index))? {
+            Stored::Valid(tx) => Some(Ok(tx)),
+            Stored::Corrupt { bytes, error } => {
+                if let Some(tx) = Self::replacement(RecordStore::Transactions, index)
+                    .and_then(|r| schema::decode_transaction(&r).ok())
+                {
+                    return Some(Ok(tx));
+                }
+                Self::quarantine(RecordStore::Transactions, index, bytes, &error);
+                Some(Err(error))
+            }
+        }
+    }
+    /// Hash of the last log entry, the `phash` of the next one. A tip that does
+    /// not decode has to be repaired first, so writers call this before they
+    /// change any state.
+    fn tip_hash() -> Result<Option<[u8; 32]>> {
+        let len = Self::get_transactions().with(|k| k.borrow().len());
+        match len.checked_sub(1).and_then(Self::get_transaction) {
+            Some(tip) => Ok(Some(tip?.hash())),
+            None => Ok(None),
+        }
+    }
+    /// Chains the transaction to the current tip, appends it and certifies the
+    /// new tip. Writers append after changing the state the entry records,
+    /// so a failed append traps to roll that change back instead of leaving
+    /// it without a block.
+    fn add_transaction(mut transaction: Transaction) -> u64 {
+        transaction.phash = match Self::tip_hash() {
+            Ok(phash) => phash,
+            Err(e) => ic_cdk::trap(&e.to_string()),
+        };
+        Self::get_transactions().with(|k| {
+            let index = k
+                .borrow_mut()
+                .append(&transaction.clone().into())
+                .unwrap_or_else(|_| ic_cdk::trap("failed to write log"));
+            certify_tip(index, transaction.hash());
+            index
+        })
+    }
+}
+
+/// Store a quarantined record was read from.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordStore {
+    Tokens,
+    Transactions,
+}
+
+impl RecordStore {
+    fn key(self) -> u8 {
+        match self {
+            RecordStore::Tokens => 0,
+            RecordStore::Transactions => 1,
+        }
+    }
+}
+
+/// A record that failed to decode, with its raw bytes for inspection.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct QuarantinedRecord {
+    pub store: RecordStore,
+    /// Definition id or log index.
+    pub key: u64,
+    pub error: DecodeError,
+    pub bytes: serde_bytes::ByteBuf,
+    pub detected_at: u64,
+    /// Repaired copy served in place of a log entry, log entries can not be
+    /// rewritten.
+    pub replacement: Option<serde_bytes::ByteBuf>,
+}
+
+impl Storable for QuarantinedRecord {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         let mut buf = vec![];
         into_writer(self, &mut buf).expect("failed to encode Collection data");
         Cow::Owned(buf)
@@ -100,33 +527,342 @@ impl Storable for Transaction {
     }
 }
 
-pub trait Icrc7TokenStorage<T>
-where
-    T: Hash + Metadata + DeserializeOwned + Serialize + 'static,
-{
-    fn get_tokens() -> &'static LocalKey<
-        std::cell::RefCell<
-            ic_stable_structures::StableBTreeMap<
-                u64,
-                TokenInner<T>,
-                ic_stable_structures::memory_manager::VirtualMemory<
-                    ic_stable_structures::DefaultMemoryImpl,
-                >,
-            >,
-        >,
-    >;
+pub type Quarantine =
+    ic_stable_structures::StableBTreeMap<(u8 /*store*/, u64 /*key*/), QuarantinedRecord, Memory>;
+
+pub trait Icrc7QuarantineStorage {
+    fn get_quarantine() -> &'static LocalKey<std::cell::RefCell<Quarantine>>;
+    /// Records a corrupt record once, later sightings keep the first one.
+    /// Only update calls persist it, queries skip the record all the same.
+    fn quarantine(store: RecordStore, key: u64, bytes: Vec<u8>, error: &DecodeError) {
+        Self::get_quarantine().with(|k| {
+            let mut quarantine = k.borrow_mut();
+            if !quarantine.contains_key(&(store.key(), key)) {
+                quarantine.insert(
+                    (store.key(), key),
+                    QuarantinedRecord {
+                        store,
+                        key,
+                        error: error.clone(),
+                        bytes: serde_bytes::ByteBuf::from(bytes),
+                        detected_at: ic_cdk::api::time(),
+                        replacement: None,
+                    },
+                );
+            }
+        })
+    }
+    fn replacement(store: RecordStore, key: u64) -> Option<Vec<u8>> {
+        Self::get_quarantine().with(|k| {
+            k.borrow()
+                .get(&(store.key(), key))?
+                .replacement
+                .map(|r| r.into_vec())
+        })
+    }
+    /// Quarantined records after the `prev` cursor.
+    fn quarantined(prev: Option<(RecordStore, u64)>, take: usize) -> Vec<QuarantinedRecord> {
+        use std::ops::Bound;
+        let start = match prev {
+            Some((store, key)) => Bound::Excluded((store.key(), key)),
+            None => Bound::Unbounded,
+        };
+        Self::get_quarantine().with(|k| {
+            k.borrow()
+                .range((start, Bound::Unbounded))
+                .take(take)
+                .map(|(_, record)| record)
+                .collect()
+        })
+    }
+}
+
+/// Requests that carried `created_at_time`, keyed by that time and the request
+/// hash, pointing at the block they produced.
+pub type DedupIndex = ic_stable_structures::StableBTreeMap<
+    (u64 /*created_at*/, [u8; 32] /*request hash*/),
+    u64, /*block index*/
+    Memory,
+>;
+
+/// Hash of a request as seen by deduplication, `args` must hold everything the
+/// caller sent, memo and created_at_time included.
+pub fn request_hash<A: Serialize>(caller: Principal, op: &str, args: &A) -> [u8; 32] {
+    let mut buf = vec![];
+    into_writer(&(caller, op, args), &mut buf).expect("failed to encode request");
+    Sha256::digest(&buf).into()
+}
+
+pub trait Icrc7DedupStorage {
+    fn get_dedup() -> &'static LocalKey<std::cell::RefCell<DedupIndex>>;
+    fn find_duplicate(created_at: u64, hash: [u8; 32]) -> Option<u64> {
+        Self::get_dedup().with(|k| k.borrow().get(&(created_at, hash)))
+    }
+    fn record_request(created_at: u64, hash: [u8; 32], block: u64) {
+        Self::get_dedup().with(|k| k.borrow_mut().insert((created_at, hash), block));
+    }
+    /// Drops every request created before `cutoff`.
+    fn prune_requests(cutoff: u64) {
+        Self::get_dedup().with(|k| {
+            let mut dedup = k.borrow_mut();
+            let expired: Vec<_> = dedup
+                .range(..(cutoff, [0; 32]))
+                .map(|(key, _)| key)
+                .collect();
+            for key in expired {
+                dedup.remove(&key);
+            }
+        })
+    }
+}
+
+/// Every (holder, token id) pair, so per-account queries are range scans.
+pub type OwnerIndex = ic_stable_structures::StableBTreeMap<(AccountKey, u64), (), Memory>;
+
+pub trait Icrc7OwnerStorage {
+    fn get_owner_index() -> &'static LocalKey<std::cell::RefCell<OwnerIndex>>;
+    fn index_holder(token_id: u64, from: Option<Account>, to: Option<Account>) {
+        Self::get_owner_index().with(|k| {
+            let mut index = k.borrow_mut();
+            if let Some(from) = from {
+                index.remove(&(from.into(), token_id));
+            }
+            if let Some(to) = to {
+                index.insert((to.into(), token_id), ());
+            }
+        })
+    }
+    fn owned_count(account: Account) -> usize {
+        let account = AccountKey(account);
+        Self::get_owner_index()
+            .with(|k| k.borrow().range((account, 0)..=(account, u64::MAX)).count())
+    }
+    /// Token ids held by `account` after the `prev` cursor.
+    fn owned_after(account: Account, prev: Option<u64>, take: usize) -> Vec<u64> {
+        use std::ops::Bound;
+        let account = AccountKey(account);
+        let start = match prev {
+            Some(prev) => Bound::Excluded((account, prev)),
+            None => Bound::Included((account, 0)),
+        };
+        Self::get_owner_index().with(|k| {
+            k.borrow()
+                .range((start, Bound::Included((account, u64::MAX))))
+                .take(take)
+                .map(|((_, token_id), _)| token_id)
+                .collect()
+        })
+    }
 }
 
-pub trait Icrc7TransactionStorage {
-    fn add_transaction(transacton: Transaction) -> Result<u64>;
+/// Every (token id, holder) pair, one small entry per live edition so that
+/// moving an edition never re-encodes its definition and a mint batch writes
+/// it only once.
+pub type Holders = ic_stable_structures::StableBTreeMap<(u64, AccountKey), (), Memory>;
+
+pub trait Icrc7HolderStorage {
+    fn get_holders() -> &'static LocalKey<std::cell::RefCell<Holders>>;
+    /// Whether any edition of a definition is live.
+    fn has_editions(definition_id: u64) -> bool {
+        Self::get_holders().with(|k| {
+            k.borrow()
+                .range((token_id(definition_id, 0), AccountKey::MIN)..)
+                .next()
+                .is_some_and(|((id, _), _)| split_token_id(id).0 == definition_id)
+        })
+    }
+}
+
+/// Live editions in the whole collection, the counts per definition are
+/// kept in [`DefinitionSupply`] so the cell stays the same size.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct SupplyCounters {
+    pub total: u64,
+}
+
+impl Storable for SupplyCounters {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode supply counters");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode supply counters")
+    }
+}
+
+pub type SupplyCell = ic_stable_structures::StableCell<SupplyCounters, Memory>;
+
+/// Live editions per definition, definitions without any have no entry.
+pub type DefinitionSupply = ic_stable_structures::StableBTreeMap<u64, u64, Memory>;
+
+/// A definition supply counter that disagreed with the holder entries.
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct SupplyDrift {
+    pub definition_id: u64,
+    pub stored: u64,
+    pub actual: u64,
+}
+
+pub trait Icrc7SupplyStorage {
+    fn get_supply() -> &'static LocalKey<std::cell::RefCell<SupplyCell>>;
+    fn get_definition_supply() -> &'static LocalKey<std::cell::RefCell<DefinitionSupply>>;
+    fn supply() -> SupplyCounters {
+        Self::get_supply().with(|k| k.borrow().get().clone())
+    }
+    fn set_supply(counters: SupplyCounters) -> Result<()> {
+        Self::get_supply().with(|k| {
+            k.borrow_mut()
+                .set(counters)
+                .map(|_| ())
+                .map_err(|_| Error::Custom("failed to write supply counters"))
+        })
+    }
+    /// Adds `delta` minted (positive) or burned (negative) editions of a
+    /// definition.
+    fn adjust_supply(definition_id: u64, delta: i64) -> Result<()> {
+        let mut counters = Self::supply();
+        counters.total = counters.total.saturating_add_signed(delta);
+        Self::get_definition_supply().with(|k| {
+            let mut supply = k.borrow_mut();
+            let count = supply
+                .get(&definition_id)
+                .unwrap_or(0)
+                .saturating_add_signed(delta);
+            if count == 0 {
+                supply.remove(&definition_id);
+            } else {
+                supply.insert(definition_id, count);
+            }
+        });
+        Self::set_supply(counters)
+    }
+    /// Sets the supply of a definition and moves the total by the
+    /// difference, returns the count it replaced.
+    fn set_definition_supply(definition_id: u64, count: u64) -> Result<u64> {
+        let stored = Self::get_definition_supply().with(|k| {
+            let mut supply = k.borrow_mut();
+            if count == 0 {
+                supply.remove(&definition_id)
+            } else {
+                supply.insert(definition_id, count)
+            }
+        });
+        let stored = stored.unwrap_or(0);
+        if count != stored {
+            let mut counters = Self::supply();
+            counters.total = (counters.total + count).saturating_sub(stored);
+            Self::set_supply(counters)?;
+        }
+        Ok(stored)
+    }
+}
+
+/// Last definition id handed out by sequential allocation.
+pub type IdCell = ic_stable_structures::StableCell<u64, Memory>;
+
+/// Ids of deleted definitions, never handed out again so burned token ids
+/// can not come back with new holders.
+pub type RetiredIds = ic_stable_structures::StableBTreeMap<u64, (), Memory>;
+
+pub trait Icrc7IdStorage {
+    fn get_last_id() -> &'static LocalKey<std::cell::RefCell<IdCell>>;
+    fn get_retired_ids() -> &'static LocalKey<std::cell::RefCell<RetiredIds>>;
+    fn is_retired(definition_id: u64) -> bool {
+        Self::get_retired_ids().with(|k| k.borrow().contains_key(&definition_id))
+    }
+}
+
+/// How `create_token` picks the id of a new definition.
+#[derive(CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdAllocation {
+    /// Next unused id from a counter that never goes back, so ids of
+    /// deleted definitions are not reused.
+    #[default]
+    Sequential,
+    /// Id chosen by the caller.
+    Given(u64),
+    /// Id derived from the content hash of the token.
+    ContentHash,
+}
+
+/// Content hash of every token definition, pointing at the definition id.
+pub type AssetIndex = ic_stable_structures::StableBTreeMap<AssetHash, u64, Memory>;
+
+/// SHA3-256 of the canonical CBOR encoding of a token.
+pub type AssetHash = [u8; 32];
+
+/// Hashes the token in deterministic CBOR (map keys sorted by their encoded
+/// bytes), so the digest only changes when the token does.
+pub fn content_hash<T: Serialize>(token: &T) -> Result<AssetHash> {
+    fn encode(value: &ciborium::Value) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(value, &mut buf).expect("failed to encode token");
+        buf
+    }
+    fn canonical(value: ciborium::Value) -> ciborium::Value {
+        use ciborium::Value as Cbor;
+        match value {
+            Cbor::Array(items) => Cbor::Array(items.into_iter().map(canonical).collect()),
+            Cbor::Tag(tag, inner) => Cbor::Tag(tag, Box::new(canonical(*inner))),
+            Cbor::Map(entries) => {
+                let mut entries: Vec<_> = entries
+                    .into_iter()
+                    .map(|(k, v)| (canonical(k), canonical(v)))
+                    .map(|(k, v)| (encode(&k), k, v))
+                    .collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                Cbor::Map(entries.into_iter().map(|(_, k, v)| (k, v)).collect())
+            }
+            value => value,
+        }
+    }
+    let value =
+        ciborium::Value::serialized(token).map_err(|_| Error::Custom("failed to encode token"))?;
+    Ok(Sha3_256::digest(encode(&canonical(value))).into())
 }
 
 pub trait Icrc7AssetsStorage {
-    fn check_asset(asset: u64) -> bool;
-    fn add_asset(asset: u64) -> Result<()>;
+    fn get_assets() -> &'static LocalKey<std::cell::RefCell<AssetIndex>>;
+    /// Definition that registered the asset, if any.
+    fn asset_token(asset: AssetHash) -> Option<u64> {
+        Self::get_assets().with(|k| k.borrow().get(&asset))
+    }
+    fn check_asset(asset: AssetHash) -> bool {
+        Self::asset_token(asset).is_some()
+    }
+    fn add_asset(asset: AssetHash, definition_id: u64) -> Result<()> {
+        Self::get_assets().with(|k| {
+            let mut assets = k.borrow_mut();
+            match assets.get(&asset) {
+                Some(id) if id != definition_id => Err(Error::Custom("asset already exists")),
+                _ => {
+                    assets.insert(asset, definition_id);
+                    Ok(())
+                }
+            }
+        })
+    }
+    fn remove_asset(asset: AssetHash) {
+        Self::get_assets().with(|k| k.borrow_mut().remove(&asset));
+    }
 }
 
-pub trait Storage<T>: Icrc7TokenStorage<T> + Icrc7TransactionStorage + Icrc7AssetsStorage
+pub trait Storage<T>:
+    Icrc7TokenStorage<T>
+    + Icrc7TransactionStorage
+    + Icrc7DedupStorage
+    + Icrc7OwnerStorage
+    + Icrc7QuarantineStorage
+    + Icrc7JobStorage
+    + Icrc7HolderStorage
+    + Icrc7SupplyStorage
+    + Icrc7IdStorage
+    + Icrc7AssetsStorage
+    + Icrc37ApprovalStorage
 where
     T: Hash + Metadata + DeserializeOwned + Serialize + 'static,
 {
@@ -136,28 +872,69 @@ pub trait Icrc7<T>: Storage<T>
 where
     T: Hash + Metadata + DeserializeOwned + Serialize + 'static,
 {
-    fn icrc7_collection_metadata(&self) -> String {
-        serde_json::to_string(&serde_json::json!({
-            "icrc7:symbol": Self::symbol(),
-            "icrc7:name": Self::name(),
-            "icrc7:description": Self::description(),
-            "icrc7:logo": Self::logo(),
-            "icrc7:total_supply": Self::total_supply(),
-            "icrc7:supply_cap": Self::supply_cap(),
-        }))
-        .unwrap_or_default()
+    /// Standard `icrc7:` collection keys followed by [`Icrc7::extra_collection_metadata`].
+    fn collection_metadata() -> Vec<(String, Value)> {
+        let nat = |n: usize| Value::Nat(Nat::from(n));
+        let mut metadata = vec![
+            ("icrc7:symbol", Value::Text(Self::symbol().to_string())),
+            ("icrc7:name", Value::Text(Self::name().to_string())),
+        ];
+        if !Self::description().is_empty() {
+            metadata.push((
+                "icrc7:description",
+                Value::Text(Self::description().to_string()),
+            ));
+        }
+        if !Self::logo().is_empty() {
+            metadata.push(("icrc7:logo", Value::Text(Self::logo().to_string())));
+        }
+        metadata.push(("icrc7:total_supply", nat(Self::total_supply())));
+        if let Some(cap) = Self::supply_cap() {
+            metadata.push(("icrc7:supply_cap", nat(cap)));
+        }
+        metadata.extend([
+            (
+                "icrc7:max_query_batch_size",
+                nat(Self::max_query_batch_size()),
+            ),
+            (
+                "icrc7:max_update_batch_size",
+                nat(Self::max_update_batch_size()),
+            ),
+            ("icrc7:default_take_value", nat(Self::default_take_value())),
+            ("icrc7:max_take_value", nat(Self::max_take_value())),
+            ("icrc7:max_memo_size", nat(Self::max_memo_size())),
+            // Value has no booleans, they are text as in JSON metadata
+            (
+                "icrc7:atomic_batch_transfers",
+                Value::Text(Self::atomic_batch_transfers().to_string()),
+            ),
+            ("icrc7:tx_window", Value::Nat(Self::tx_window().into())),
+            (
+                "icrc7:permitted_drift",
+                Value::Nat(Self::permitted_drift().into()),
+            ),
+        ]);
+        metadata
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .chain(Self::extra_collection_metadata())
+            .collect()
+    }
+    /// User-defined collection keys, set with `#[icrc7(collection_metadata(...))]`.
+    fn extra_collection_metadata() -> Vec<(String, Value)> {
+        vec![]
     }
     fn symbol() -> &'static str;
     fn name() -> &'static str;
     fn description() -> &'static str;
     fn logo() -> &'static str;
     fn total_supply() -> usize {
-        Self::get_tokens().with(|k| {
-            (*k.borrow()).iter().fold(0, |mut s, (_, t)| {
-                s += t.holders.len();
-                s
-            })
-        })
+        Self::supply().total as usize
+    }
+    /// Live editions of a single definition.
+    fn definition_supply(definition_id: u64) -> usize {
+        Self::get_definition_supply().with(|k| k.borrow().get(&definition_id).unwrap_or(0)) as usize
     }
     fn supply_cap() -> Option<usize> {
         None
@@ -180,264 +957,815 @@ where
     fn atomic_batch_transfers() -> bool {
         false
     }
-    fn tx_window() -> usize {
+    /// Principals allowed to burn tokens they do not hold, e.g. to retire
+    /// works after a takedown request.
+    fn authorized_burners() -> Vec<Principal> {
+        vec![]
+    }
+    /// Seconds, as ICRC-7 reports it.
+    fn tx_window() -> u64 {
         2 * 60 * 60
     }
-    fn permitted_drift() -> usize {
+    /// Seconds, as ICRC-7 reports it.
+    fn permitted_drift() -> u64 {
         2 * 60
     }
-    fn token_metadata(token_ids: Vec<u64>) -> Result<Vec<String>> {
+    /// `created_at` and `now` are nanoseconds, the window and drift are
+    /// converted to match.
+    fn check_created_at(created_at: u64, now: u64) -> std::result::Result<(), TimeError> {
+        check_created_at(created_at, now, Self::tx_window(), Self::permitted_drift())
+    }
+    /// Forgets requests that fell out of the dedup window, they are rejected
+    /// as too old from then on.
+    fn prune_dedup() {
+        let window = Self::tx_window()
+            .saturating_add(Self::permitted_drift())
+            .saturating_mul(1_000_000_000);
+        Self::prune_requests(ic_cdk::api::time().saturating_sub(window));
+    }
+    fn start_dedup_pruning()
+    where
+        Self: 'static,
+    {
+        ic_cdk_timers::set_timer_interval(
+            Duration::from_secs(Self::tx_window()),
+            Self::prune_dedup,
+        );
+    }
+    /// Metadata of each requested token in request order, `None` for unknown
+    /// and burned tokens.
+    fn token_metadata(token_ids: Vec<u64>) -> Result<Vec<Option<TokenMetadata>>> {
+        if token_ids.len() > Self::max_query_batch_size() {
+            Err(Error::Custom("exceeds max query batch size"))
+        } else {
+            Ok(token_ids
+                .iter()
+                .map(|id| {
+                    let (definition_id, edition) = split_token_id(*id);
+                    Self::holder_of(*id)?;
+                    let token = Self::get_token(definition_id).ok().flatten()?;
+                    Some(token.edition_metadata(edition))
+                })
+                .collect())
+        }
+    }
+    /// Current holder of an edition, `None` for unknown and burned tokens.
+    fn holder_of(token_id: u64) -> Option<Account> {
+        Self::get_holders().with(|k| {
+            k.borrow()
+                .range((token_id, AccountKey::MIN)..)
+                .next()
+                .and_then(|((id, holder), _)| (id == token_id).then_some(holder.0))
+        })
+    }
+    /// Moves an existing edition to `to`, `None` burns it.
+    fn set_holder(token_id: u64, to: Option<Account>) {
+        if let Some(from) = Self::holder_of(token_id) {
+            Self::get_holders().with(|k| {
+                let mut holders = k.borrow_mut();
+                holders.remove(&(token_id, from.into()));
+                if let Some(to) = to {
+                    holders.insert((token_id, to.into()), ());
+                }
+            });
+            Self::index_holder(token_id, Some(from), to);
+        }
+    }
+    /// Holder of each requested token in request order.
+    fn owner_of(token_ids: Vec<u64>) -> Result<Vec<Option<Account>>> {
         if token_ids.len() > Self::max_query_batch_size() {
             Err(Error::Custom("exceeds max query batch size"))
         } else {
-            Ok(Self::get_tokens().with(|k| {
-                k.borrow()
-                    .iter()
-                    .filter(|(id, _)| token_ids.contains(id))
-                    .map(|(_id, token)| token.token.metadata())
-                    .collect()
-            }))
+            Ok(token_ids.into_iter().map(Self::holder_of).collect())
         }
     }
-    fn owner_of(token_ids: Vec<u64>) -> Result<Vec<Option<Principal>>> {
+    /// Creator of the definition each token was minted from, `None` for
+    /// unknown and burned tokens.
+    fn creator_of(token_ids: Vec<u64>) -> Result<Vec<Option<Account>>> {
         if token_ids.len() > Self::max_query_batch_size() {
             Err(Error::Custom("exceeds max query batch size"))
         } else {
-            Self::get_tokens().with(|k| {
-                let tokens = k.borrow();
-                Ok(token_ids
-                    .iter()
-                    .map(|id| match tokens.get(id) {
-                        Some(t) => Some(t.owner.clone()),
-                        _ => None,
-                    })
-                    .collect())
-            })
+            Ok(token_ids
+                .iter()
+                .map(|id| {
+                    Self::holder_of(*id)?;
+                    let token = Self::get_token(split_token_id(*id).0).ok().flatten()?;
+                    Some(token.creator)
+                })
+                .collect())
         }
     }
-    fn balance_of(accounts: Vec<Principal>) -> Result<Vec<usize>> {
+    fn balance_of(accounts: Vec<Account>) -> Result<Vec<usize>> {
         if accounts.len() > Self::max_query_batch_size() {
             Err(Error::Custom("exceeds max query batch size"))
         } else {
-            Self::get_tokens().with(|k| {
-                let tokens = k.borrow();
-                Ok(accounts
-                    .iter()
-                    .map(|owner| {
-                        tokens
-                            .iter()
-                            .filter(|(_, t)| t.holders.contains(owner))
-                            .count()
-                    })
-                    .collect())
-            })
+            Ok(accounts.into_iter().map(Self::owned_count).collect())
         }
     }
-    fn tokens(prev: Option<usize>, take: Option<usize>) -> Result<Vec<u64>> {
-        Self::get_tokens().with(|k| {
-            let tokens = k.borrow();
-            Ok(tokens
-                .iter()
-                .skip(prev.unwrap_or(0))
-                .take(take.unwrap_or(Self::max_query_batch_size()))
-                .map(|(id, _)| id.clone())
+    /// Token ids after `prev`, the last id of the previous page.
+    fn tokens(prev: Option<u64>, take: Option<usize>) -> Result<Vec<u64>> {
+        let take = take.unwrap_or(Self::default_take_value());
+        if take > Self::max_take_value() {
+            return Err(Error::Custom("exceeds max take value"));
+        }
+        let start = prev.map_or(0, |p| p.saturating_add(1));
+        Self::get_holders().with(|k| {
+            Ok(k.borrow()
+                .range((start, AccountKey::MIN)..)
+                .filter(|((id, _), _)| prev.is_none_or(|p| *id > p))
+                .take(take)
+                .map(|((id, _), _)| id)
                 .collect())
         })
     }
-    fn tokens_of(account: Principal, prev: Option<usize>, take: Option<usize>) -> Result<Vec<u64>> {
-        Self::get_tokens().with(|k| {
-            let tokens = k.borrow();
-            Ok(tokens
-                .iter()
-                .filter(|(_, t)| t.holders.contains(&account))
-                .skip(prev.unwrap_or(0))
-                .take(take.unwrap_or(Self::max_query_batch_size()))
-                .map(|(i, _)| i)
-                .collect())
-        })
+    fn tokens_of(account: Account, prev: Option<u64>, take: Option<usize>) -> Result<Vec<u64>> {
+        let take = take.unwrap_or(Self::default_take_value());
+        if take > Self::max_take_value() {
+            return Err(Error::Custom("exceeds max take value"));
+        }
+        Ok(Self::owned_after(account, prev, take))
     }
-    fn create_token(token: T, supply_cap: Option<usize>) -> Result<u64> {
-        use std::hash::Hasher;
+    /// Brings one stored definition up to date: rewrites an older layout,
+    /// moves up to `editions` holders kept inside it into the holder entries
+    /// and fills in a missing content hash, returns whether holders are left
+    /// to move. Once none are, the hash is registered in the asset index when
+    /// it was missing or `index_asset` is set.
+    fn migrate_definition(
+        definition_id: u64,
+        token: Stored<TokenInner<T>>,
+        index_asset: bool,
+        editions: usize,
+    ) -> Result<bool> {
+        let mut token = match token {
+            Stored::Valid(t) => t,
+            Stored::Corrupt { bytes, error } => {
+                Self::quarantine(RecordStore::Tokens, definition_id, bytes, &error);
+                return Err(error.into());
+            }
+        };
+        let mut changed = token.version < SCHEMA_VERSION;
+        if !token.editions.is_empty() {
+            Self::get_holders().with(|h| {
+                let mut holders = h.borrow_mut();
+                for _ in 0..editions {
+                    let Some((edition, holder)) = token.editions.pop_first() else {
+                        break;
+                    };
+                    holders.insert((token_id(definition_id, edition), holder.into()), ());
+                }
+            });
+            changed = true;
+        }
+        let (hash, index_asset) = match token.content_hash {
+            Some(hash) => (hash, index_asset),
+            None => {
+                let hash = content_hash(&token.token)?;
+                token.content_hash = Some(hash);
+                changed = true;
+                (hash, true)
+            }
+        };
+        let left = !token.editions.is_empty();
+        if changed {
+            Self::get_tokens().with(|k| k.borrow_mut().insert(definition_id, token.into()));
+        }
+        if index_asset && !left {
+            Self::add_asset(hash, definition_id)?;
+        }
+        Ok(left)
+    }
+    /// Picks a free definition id, definition ids must fit in
+    /// `64 - EDITION_BITS` bits.
+    fn allocate_token_id(allocation: IdAllocation, token_hash: AssetHash) -> Result<u64> {
+        const MAX_ID: u64 = u64::MAX >> EDITION_BITS;
+        let in_use = |id: u64| {
+            Self::get_tokens().with(|k| k.borrow().contains_key(&id)) || Self::is_retired(id)
+        };
+        let id = match allocation {
+            IdAllocation::Sequential => {
+                return Self::get_last_id().with(|k| {
+                    let mut last = k.borrow_mut();
+                    let mut id = *last.get() + 1;
+                    while id <= MAX_ID && in_use(id) {
+                        id += 1;
+                    }
+                    if id > MAX_ID {
+                        return Err(Error::Custom("no token definition ids left"));
+                    }
+                    last.set(id)
+                        .map_err(|_| Error::Custom("failed to store last token id"))?;
+                    Ok(id)
+                })
+            }
+            IdAllocation::Given(id) => id,
+            IdAllocation::ContentHash => {
+                let mut id = [0; 8];
+                id.copy_from_slice(&token_hash[..8]);
+                u64::from_be_bytes(id) & MAX_ID
+            }
+        };
+        if id == 0 || id > MAX_ID {
+            return Err(Error::Custom("token id out of range"));
+        }
+        if Self::is_retired(id) {
+            return Err(Error::Custom("token id belonged to a deleted definition"));
+        }
+        if in_use(id) {
+            return Err(Error::Custom("token id already in use"));
+        }
+        Ok(id)
+    }
+    fn create_token(token: T, supply_cap: Option<usize>, allocation: IdAllocation) -> Result<u64> {
         let author = ic_cdk::caller();
-        let now_sec = ic_cdk::api::time() / 1_000_000_000;
-        let mut token_hash = std::hash::DefaultHasher::new();
-        token.hash(&mut token_hash);
-        let token_hash = token_hash.finish();
+        let now = ic_cdk::api::time();
+        let token_hash = content_hash(&token)?;
         if Self::check_asset(token_hash) {
             return Err(Error::Custom("asset already exists"));
         }
-        let id = Self::get_tokens().with(|k| {
-            let tokens = k.borrow();
-            tokens.len() as u64 + 1
-        });
+        let id = Self::allocate_token_id(allocation, token_hash)?;
         let ttoken = TokenInner {
             id,
             token,
             supply_cap,
-            created_at: now_sec,
-            updated_at: now_sec,
-            owner: author,
-            holders: HashSet::new(),
+            created_at: now,
+            updated_at: now,
+            creator: author.into(),
+            minted: 0,
+            editions: BTreeMap::new(),
+            content_hash: Some(token_hash),
+            version: SCHEMA_VERSION,
         };
         Self::get_tokens().with(|k| {
             let mut tokens = k.borrow_mut();
-            tokens.insert(id, ttoken);
+            tokens.insert(id, ttoken.into());
         });
-        Self::add_asset(token_hash)?;
+        Self::add_asset(token_hash, id)?;
         Ok(id)
     }
-    fn update_token(id: u64, token: T, supply_cap: Option<usize>) -> Result<()> {
+    fn update_token(definition_id: u64, token: T, supply_cap: Option<usize>) -> Result<()> {
         let caller = ic_cdk::caller();
-        Self::get_tokens().with(|k| {
-            let mut tokens = k.borrow_mut();
-
-            let mut orig_token = match tokens.remove(&id) {
-                Some(t) => t,
-                None => {
-                    return Err(Error::Custom("token not found"));
-                }
-            };
-            if orig_token.owner != caller {
-                return Err(Error::Custom("caller is not a owner"));
+        let mut orig_token = match Self::get_token(definition_id)? {
+            Some(t) => t,
+            None => {
+                return Err(Error::Custom("token not found"));
             }
-            if orig_token.total_supply() > 0 {
-                return Err(Error::Custom("token has been minted, can not be updated"));
+        };
+        if orig_token.creator.owner != caller {
+            return Err(Error::Custom("caller is not a owner"));
+        }
+        if orig_token.minted > 0 {
+            return Err(Error::Custom("token has been minted, can not be updated"));
+        }
+        if let Some(supply_cap) = supply_cap {
+            if supply_cap >= orig_token.supply_cap.unwrap_or(0) {
+                return Err(Error::Custom("supply cap can not be increased"));
             }
-            if let Some(supply_cap) = supply_cap {
-                if supply_cap >= orig_token.supply_cap.unwrap_or(0) {
-                    return Err(Error::Custom("supply cap can not be increased"));
+        }
+        let old_hash = match orig_token.content_hash {
+            Some(hash) => hash,
+            None => content_hash(&orig_token.token)?,
+        };
+        let new_hash = content_hash(&token)?;
+        if new_hash != old_hash {
+            Self::add_asset(new_hash, definition_id)?;
+            Self::remove_asset(old_hash);
+        }
+        orig_token.content_hash = Some(new_hash);
+        orig_token.updated_at = ic_cdk::api::time();
+        orig_token.token = token;
+        Self::get_tokens().with(|k| k.borrow_mut().insert(definition_id, orig_token.into()));
+        Ok(())
+    }
+    /// Removes a definition with no live editions and releases its asset hash,
+    /// its id is retired.
+    fn delete_token(definition_id: u64) -> Result<()> {
+        let caller = ic_cdk::caller();
+        let token = match Self::get_token(definition_id)? {
+            Some(t) => t,
+            None => return Err(Error::Custom("token not found")),
+        };
+        if token.creator.owner != caller {
+            return Err(Error::Custom("caller is not a owner"));
+        }
+        if !token.editions.is_empty() || Self::has_editions(definition_id) {
+            return Err(Error::Custom("token has live editions, can not be deleted"));
+        }
+        let hash = match token.content_hash {
+            Some(hash) => hash,
+            None => content_hash(&token.token)?,
+        };
+        Self::remove_asset(hash);
+        Self::get_retired_ids().with(|k| k.borrow_mut().insert(definition_id, ()));
+        Self::get_tokens().with(|k| k.borrow_mut().remove(&definition_id));
+        Ok(())
+    }
+    /// Records that failed to decode after the `prev` cursor, controllers
+    /// only.
+    fn quarantined_records(
+        prev: Option<(RecordStore, u64)>,
+        take: Option<usize>,
+    ) -> Result<Vec<QuarantinedRecord>> {
+        if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+            return Err(Error::Custom("caller is not a controller"));
+        }
+        let take = take.unwrap_or(Self::default_take_value());
+        if take > Self::max_take_value() {
+            return Err(Error::Custom("exceeds max take value"));
+        }
+        Ok(Self::quarantined(prev, take))
+    }
+    /// Replaces a quarantined record with `bytes`, which must decode, or drops
+    /// a quarantined definition when `bytes` is `None`. Log entries can not be
+    /// rewritten, their replacement is served in their place. Controllers
+    /// only.
+    fn repair_record(store: RecordStore, key: u64, bytes: Option<Vec<u8>>) -> Result<()> {
+        if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+            return Err(Error::Custom("caller is not a controller"));
+        }
+        let mut record = match Self::get_quarantine().with(|k| k.borrow().get(&(store.key(), key)))
+        {
+            Some(r) => r,
+            None => return Err(Error::Custom("record is not quarantined")),
+        };
+        match (store, bytes) {
+            (RecordStore::Tokens, Some(bytes)) => {
+                let token = schema::decode_token::<T>(&bytes)?;
+                Self::get_tokens().with(|k| k.borrow_mut().insert(key, token.into()));
+            }
+            (RecordStore::Tokens, None) => {
+                Self::get_tokens().with(|k| k.borrow_mut().remove(&key));
+            }
+            (RecordStore::Transactions, Some(bytes)) => {
+                let tx = schema::decode_transaction(&bytes)?;
+                record.replacement = Some(serde_bytes::ByteBuf::from(bytes));
+                Self::get_quarantine().with(|k| k.borrow_mut().insert((store.key(), key), record));
+                if Self::get_transactions().with(|k| k.borrow().len()) == key + 1 {
+                    certify_tip(key, tx.hash());
                 }
+                return Ok(());
             }
-            let now = ic_cdk::api::time() / 1_000_000_000;
-            orig_token.updated_at = now;
-            orig_token.token = token;
-            tokens.insert(id, orig_token);
-            Ok(())
-        })
+            (RecordStore::Transactions, None) => {
+                return Err(Error::Custom("log entries can not be removed"));
+            }
+        }
+        Self::get_quarantine().with(|k| k.borrow_mut().remove(&(store.key(), key)));
+        Ok(())
     }
+    #[allow(clippy::type_complexity)]
     fn transfer(
         args: Vec<(
-            u64,          /*token_id*/
-            Principal,    /*to*/
-            Option<Memo>, /*memo*/
-            Option<u64>,  /*created_at*/
+            u64,                /*token_id*/
+            Option<Subaccount>, /*from_subaccount*/
+            Account,            /*to*/
+            Option<Memo>,       /*memo*/
+            Option<u64>,        /*created_at*/
         )>,
-    ) -> Result<Vec<Result<u64>>> {
-        Self::get_tokens().with(|k| {
-            let mut tokens = k.borrow_mut();
-            if args.is_empty() {
-                return Err(Error::Custom("no transfer args provided"));
-            }
+    ) -> std::result::Result<Vec<std::result::Result<u64, TransferError>>, TransferError> {
+        if args.is_empty() {
+            return Err(TransferError::batch(Error::Custom(
+                "no transfer args provided",
+            )));
+        }
 
-            if args.len() > Self::max_update_batch_size() {
-                return Err(Error::Custom("exceeds max update batch size"));
-            }
+        if args.len() > Self::max_update_batch_size() {
+            return Err(TransferError::batch(Error::Custom(
+                "exceeds max update batch size",
+            )));
+        }
 
-            let caller = ic_cdk::caller();
-            let now = ic_cdk::api::time();
-            let mut res = args.iter().fold(vec![], |mut res, arg| {
-                let mut rres = Ok(());
-                if arg.1 == Principal::anonymous() || arg.1 == caller {
-                    rres = Err(Error::Custom("invalid recipient"));
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        let res: Vec<std::result::Result<Option<(u64, [u8; 32])>, TransferError>> = args
+            .iter()
+            .map(|arg| {
+                let from = Account {
+                    owner: caller,
+                    subaccount: arg.1,
+                };
+                if arg.2.owner == Principal::anonymous() || arg.2 == from {
+                    return Err(TransferError::InvalidRecipient);
                 }
-                if let Some(mm) = &arg.2 {
+                if let Some(mm) = &arg.3 {
                     if mm.0.len() > Self::max_memo_size() {
-                        rres = Err(Error::Custom("memo size too large"));
+                        return Err(Error::Custom("memo size too large").into());
                     }
                 }
-                if let Some(ct) = arg.3 {
-                    if ct > now + Self::permitted_drift() as u64 {
-                        rres = Err(Error::Custom("too old"));
-                    }
+                if let Some(ct) = arg.4 {
+                    Self::check_created_at(ct, now)?;
                 }
-                match tokens.get(&arg.0) {
-                    Some(t) => {
-                        if !t.holders.contains(&caller) {
-                            rres = Err(Error::Custom("unauthorized"));
-                        }
-                    }
-                    None => {
-                        rres = Err(Error::Custom("non existing token"));
-                    }
+                let dedup = arg.4.map(|ct| (ct, request_hash(caller, "7xfr", arg)));
+                if let Some(duplicate_of) = dedup.and_then(|(ct, h)| Self::find_duplicate(ct, h)) {
+                    return Err(TransferError::Duplicate {
+                        duplicate_of: duplicate_of.into(),
+                    });
                 }
-                res.push(rres);
-                res
-            });
-            if Self::atomic_batch_transfers() && args.len() > 1 && res.iter().any(|r| r.is_err()) {
-                return Err(Error::Custom("invalid transfer args"));
-            }
-            Ok(args
-                .iter()
-                .zip(res.iter_mut())
-                .filter(|(_arg, res)| res.is_ok())
-                .fold(vec![], |mut res, (arg, _res)| {
-                    let mut orig_token = tokens.get(&arg.0).unwrap();
-                    let hh = &mut orig_token.holders;
-                    hh.remove(&caller);
-                    hh.insert(arg.1);
-                    tokens.insert(arg.0, orig_token);
-                    let tx_log = Transaction {
-                        ts: now,
-                        token_id: arg.0,
-                        op: "7xfr".to_string(),
-                        from: Some(caller),
-                        to: Some(arg.1),
-                        metadata: tokens.get(&arg.0).unwrap().token.metadata(),
-                        memo: arg.2.clone(),
-                    };
-                    res.push(Self::add_transaction(tx_log));
-                    res
-                }))
-        })
+                match Self::holder_of(arg.0) {
+                    Some(holder) if holder == from => Ok(dedup),
+                    Some(_) => Err(TransferError::Unauthorized),
+                    None => Err(TransferError::NonExistingTokenId),
+                }
+            })
+            .collect();
+        if Self::atomic_batch_transfers() && args.len() > 1 && res.iter().any(|r| r.is_err()) {
+            return Err(TransferError::batch(Error::Custom("invalid transfer args")));
+        }
+        Self::tip_hash().map_err(TransferError::batch)?;
+        Ok(args
+            .iter()
+            .zip(res)
+            .map(|(arg, res)| {
+                let dedup = res?;
+                // repeated args within the same batch
+                if let Some(duplicate_of) = dedup.and_then(|(ct, h)| Self::find_duplicate(ct, h)) {
+                    return Err(TransferError::Duplicate {
+                        duplicate_of: duplicate_of.into(),
+                    });
+                }
+                let from = Account {
+                    owner: caller,
+                    subaccount: arg.1,
+                };
+                // an earlier entry of the batch may have moved the token
+                if Self::holder_of(arg.0) != Some(from) {
+                    return Err(TransferError::Unauthorized);
+                }
+                Self::set_holder(arg.0, Some(arg.2));
+                clear_token_approvals::<Self>(arg.0, from);
+                let tx_log = Transaction {
+                    ts: now,
+                    token_id: Some(arg.0),
+                    op: "7xfr".to_string(),
+                    from: Some(from),
+                    to: Some(arg.2),
+                    meta: None,
+                    memo: arg.3.clone(),
+                    spender: None,
+                    exp: None,
+                    phash: None,
+                    version: SCHEMA_VERSION,
+                };
+                let index = Self::add_transaction(tx_log);
+                if let Some((ct, h)) = dedup {
+                    Self::record_request(ct, h, index);
+                }
+                Ok(index)
+            })
+            .collect())
     }
 
-    fn mint(token_id: u64, holders: HashSet<Principal>) -> Result<Vec<Result<u64>>> {
-        Self::get_tokens().with(|k| {
-            let mut tokens = k.borrow_mut();
-            let caller = ic_cdk::caller();
-            if holders.is_empty() {
-                return Err(Error::Custom("no mint holders provided"));
-            }
-            if holders.len() > Self::max_update_batch_size() {
-                return Err(Error::Custom("exceeds max update batch size"));
-            }
-            let token = match tokens.get(&token_id) {
-                Some(t) => {
-                    if let Some(sp) = t.supply_cap {
-                        if t.total_supply() + holders.len() > sp {
-                            return Err(Error::Custom("token supply capability reached"));
-                        }
-                    }
-                    t
-                }
-                None => return Err(Error::Custom("non existing token id")),
-            };
-            if token.owner != caller {
-                return Err(Error::Custom("unauthorized"));
-            }
+    /// Mints one new edition of the definition for every entry in `holders`.
+    fn mint(
+        definition_id: u64,
+        holders: Vec<Account>,
+        memo: Option<Memo>,
+        created_at: Option<u64>,
+    ) -> std::result::Result<Vec<std::result::Result<u64, MintError>>, MintError> {
+        Self::mint_by(ic_cdk::caller(), definition_id, holders, memo, created_at)
+    }
+    /// [`Icrc7::mint`] on behalf of `caller`, for work that runs outside the
+    /// caller's own message such as jobs.
+    fn mint_by(
+        caller: Principal,
+        definition_id: u64,
+        holders: Vec<Account>,
+        memo: Option<Memo>,
+        created_at: Option<u64>,
+    ) -> std::result::Result<Vec<std::result::Result<u64, MintError>>, MintError> {
+        if holders.is_empty() {
+            return Err(MintError::batch(Error::Custom("no mint holders provided")));
+        }
+        if holders.len() > Self::max_update_batch_size() {
+            return Err(MintError::batch(Error::Custom(
+                "exceeds max update batch size",
+            )));
+        }
+        if memo
+            .as_ref()
+            .is_some_and(|m| m.0.len() > Self::max_memo_size())
+        {
+            return Err(MintError::batch(Error::Custom("memo size too large")));
+        }
+        let mut token = match Self::get_token(definition_id) {
+            Ok(Some(t)) => t,
+            Ok(None) => return Err(MintError::NonExistingTokenId),
+            Err(e) => return Err(MintError::batch(e.into())),
+        };
+        if token.creator.owner != caller {
+            return Err(MintError::Unauthorized);
+        }
+        let minted = token.minted as usize + holders.len();
+        if token.supply_cap.is_some_and(|cap| minted > cap) || minted > u32::MAX as usize {
+            return Err(MintError::SupplyCapReached);
+        }
 
-            let now = ic_cdk::api::time();
-            Ok(holders.iter().fold(vec![], |mut res, holder| {
-                let mut token = tokens.get(&token_id).unwrap();
-                let holders = &mut token.holders;
-                holders.insert(holder.clone());
-                let metadata = token.token.metadata();
-                tokens.insert(token_id, token);
+        let now = ic_cdk::api::time();
+        if let Some(ct) = created_at {
+            Self::check_created_at(ct, now)?;
+        }
+        Self::tip_hash().map_err(MintError::batch)?;
+        let minted = token.minted;
+        let res = holders
+            .iter()
+            .enumerate()
+            .map(|(i, holder)| {
+                if holder.owner == Principal::anonymous() {
+                    return Err(MintError::InvalidRecipient);
+                }
+                let dedup = created_at.map(|ct| {
+                    let args = (definition_id, i, holder, &memo, ct);
+                    (ct, request_hash(caller, "7mint", &args))
+                });
+                if let Some(duplicate_of) = dedup.and_then(|(ct, h)| Self::find_duplicate(ct, h)) {
+                    return Err(MintError::Duplicate {
+                        duplicate_of: duplicate_of.into(),
+                    });
+                }
+                token.minted += 1;
+                let edition = token.minted;
+                let meta = Value::Map(token.edition_metadata(edition).into_iter().collect());
+                Self::get_holders().with(|k| {
+                    k.borrow_mut()
+                        .insert((token_id(definition_id, edition), (*holder).into()), ())
+                });
+                Self::index_holder(token_id(definition_id, edition), None, Some(*holder));
+                Self::adjust_supply(definition_id, 1)?;
                 let tx_log = Transaction {
                     ts: now,
-                    token_id,
+                    token_id: Some(token_id(definition_id, edition)),
                     op: "7mint".to_string(),
-                    from: Some(caller),
-                    to: Some(holder.clone()),
-                    metadata,
-                    memo: None,
+                    from: Some(caller.into()),
+                    to: Some(*holder),
+                    meta: Some(meta),
+                    memo: memo.clone(),
+                    spender: None,
+                    exp: None,
+                    phash: None,
+                    version: SCHEMA_VERSION,
                 };
-                res.push(Self::add_transaction(tx_log));
-                res
-            }))
-        })
+                let index = Self::add_transaction(tx_log);
+                if let Some((ct, h)) = dedup {
+                    Self::record_request(ct, h, index);
+                }
+                Ok(index)
+            })
+            .collect();
+        // the definition is written once per batch, editions only bump its
+        // counter
+        if token.minted != minted {
+            Self::get_tokens().with(|k| k.borrow_mut().insert(definition_id, token.into()));
+        }
+        Ok(res)
+    }
+    /// Removes `holder` (the caller's account by default) from the token and
+    /// logs a `7burn` block, only authorized burners can burn for others.
+    #[allow(clippy::type_complexity)]
+    fn burn(
+        args: Vec<(
+            u64,                /*token_id*/
+            Option<Subaccount>, /*from_subaccount*/
+            Option<Account>,    /*holder*/
+            Option<Memo>,       /*memo*/
+            Option<u64>,        /*created_at*/
+        )>,
+    ) -> std::result::Result<Vec<std::result::Result<u64, BurnError>>, BurnError> {
+        if args.is_empty() {
+            return Err(BurnError::batch(Error::Custom("no burn args provided")));
+        }
+        if args.len() > Self::max_update_batch_size() {
+            return Err(BurnError::batch(Error::Custom(
+                "exceeds max update batch size",
+            )));
+        }
+        let caller = ic_cdk::caller();
+        let now = ic_cdk::api::time();
+        let is_burner = Self::authorized_burners().contains(&caller);
+        let res: Vec<std::result::Result<Option<(u64, [u8; 32])>, BurnError>> = args
+            .iter()
+            .map(|arg| {
+                let (token_id, from_subaccount, holder, memo, created_at) = arg;
+                let caller_account = Account {
+                    owner: caller,
+                    subaccount: *from_subaccount,
+                };
+                let holder = holder.unwrap_or(caller_account);
+                if holder != caller_account && !is_burner {
+                    return Err(BurnError::Unauthorized);
+                }
+                if memo
+                    .as_ref()
+                    .is_some_and(|m| m.0.len() > Self::max_memo_size())
+                {
+                    return Err(Error::Custom("memo size too large").into());
+                }
+                if let Some(ct) = *created_at {
+                    Self::check_created_at(ct, now)?;
+                }
+                let dedup = created_at.map(|ct| (ct, request_hash(caller, "7burn", arg)));
+                if let Some(duplicate_of) = dedup.and_then(|(ct, h)| Self::find_duplicate(ct, h)) {
+                    return Err(BurnError::Duplicate {
+                        duplicate_of: duplicate_of.into(),
+                    });
+                }
+                match Self::holder_of(*token_id) {
+                    Some(h) if h == holder => Ok(dedup),
+                    Some(_) => Err(BurnError::Unauthorized),
+                    None => Err(BurnError::NonExistingTokenId),
+                }
+            })
+            .collect();
+        if Self::atomic_batch_transfers() && args.len() > 1 && res.iter().any(|r| r.is_err()) {
+            return Err(BurnError::batch(Error::Custom("invalid burn args")));
+        }
+        Self::tip_hash().map_err(BurnError::batch)?;
+        Ok(args
+            .into_iter()
+            .zip(res)
+            .map(
+                |((token_id, from_subaccount, holder, memo, _created_at), res)| {
+                    let dedup = res?;
+                    if let Some(duplicate_of) =
+                        dedup.and_then(|(ct, h)| Self::find_duplicate(ct, h))
+                    {
+                        return Err(BurnError::Duplicate {
+                            duplicate_of: duplicate_of.into(),
+                        });
+                    }
+                    let caller_account = Account {
+                        owner: caller,
+                        subaccount: from_subaccount,
+                    };
+                    let holder = holder.unwrap_or(caller_account);
+                    if Self::holder_of(token_id) != Some(holder) {
+                        return Err(BurnError::Unauthorized);
+                    }
+                    Self::set_holder(token_id, None);
+                    Self::adjust_supply(split_token_id(token_id).0, -1)?;
+                    clear_token_approvals::<Self>(token_id, holder);
+                    let index = Self::add_transaction(Transaction {
+                        ts: now,
+                        token_id: Some(token_id),
+                        op: "7burn".to_string(),
+                        from: Some(holder),
+                        to: None,
+                        meta: None,
+                        memo,
+                        spender: (holder != caller_account).then_some(caller_account),
+                        exp: None,
+                        phash: None,
+                        version: SCHEMA_VERSION,
+                    });
+                    if let Some((ct, h)) = dedup {
+                        Self::record_request(ct, h, index);
+                    }
+                    Ok(index)
+                },
+            )
+            .collect())
     }
 }
 
 pub use ugd_derive::Icrc7;
 pub use ugd_derive::Storage;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Serialize)]
+    struct Token {
+        b: u8,
+        aa: u8,
+    }
+
+    #[derive(Serialize)]
+    struct Reordered {
+        aa: u8,
+        b: u8,
+    }
+
+    const SEC: u64 = 1_000_000_000;
+
+    fn account(owner: &[u8], subaccount: Option<Subaccount>) -> Account {
+        Account {
+            owner: Principal::from_slice(owner),
+            subaccount,
+        }
+    }
+
+    #[test]
+    fn account_key_round_trips() {
+        for account in [
+            account(&[], None),
+            account(&[1; 29], None),
+            account(&[2; 10], Some([7; 32])),
+            account(&[3; 29], Some([0xff; 32])),
+        ] {
+            let bytes = AccountKey(account).to_bytes().into_owned();
+            assert_eq!(bytes.len(), 62);
+            assert_eq!(AccountKey::from_bytes(Cow::Owned(bytes)).0, account);
+        }
+        // the default subaccount is the same account as none
+        let default = AccountKey(account(&[1; 29], Some([0; 32])));
+        assert_eq!(
+            AccountKey::from_bytes(default.to_bytes()).0,
+            account(&[1; 29], None)
+        );
+    }
+
+    #[test]
+    fn account_key_order() {
+        let mut keys = vec![
+            AccountKey(account(&[2], None)),
+            AccountKey(account(&[1, 0], Some([1; 32]))),
+            AccountKey(account(&[1, 0], None)),
+            AccountKey(account(&[1], Some([0xff; 32]))),
+            AccountKey(account(&[], Some([1; 32]))),
+        ];
+        keys.sort();
+        assert!(keys.iter().all(|k| AccountKey::MIN < *k));
+        let decoded: Vec<_> = keys
+            .iter()
+            .map(|k| AccountKey::from_bytes(k.to_bytes()))
+            .collect();
+        assert_eq!(decoded, keys);
+        // accounts of one owner are contiguous, none first
+        let first = keys
+            .iter()
+            .position(|k| k.0 == account(&[1, 0], None))
+            .unwrap();
+        assert_eq!(keys[first + 1].0, account(&[1, 0], Some([1; 32])));
+    }
+
+    #[test]
+    fn created_at_window_and_drift_bounds() {
+        let now = 10_000 * SEC;
+        let oldest = now - (100 + 10) * SEC;
+        assert_eq!(check_created_at(oldest, now, 100, 10), Ok(()));
+        assert_eq!(
+            check_created_at(oldest - 1, now, 100, 10),
+            Err(TimeError::TooOld)
+        );
+        assert_eq!(check_created_at(now + 10 * SEC, now, 100, 10), Ok(()));
+        assert_eq!(
+            check_created_at(now + 10 * SEC + 1, now, 100, 10),
+            Err(TimeError::CreatedInFuture { ledger_time: now })
+        );
+    }
+
+    #[test]
+    fn created_at_saturates() {
+        // the window reaches back past 0
+        assert_eq!(check_created_at(0, 5 * SEC, 100, 10), Ok(()));
+        // windows too large for nanoseconds are unbounded
+        assert_eq!(check_created_at(0, u64::MAX, u64::MAX, 0), Ok(()));
+        // drift reaches past the end of time
+        assert_eq!(check_created_at(u64::MAX, u64::MAX - SEC, 0, 10), Ok(()));
+        // without a window or drift only `now` itself passes
+        assert_eq!(check_created_at(1, 1, 0, 0), Ok(()));
+        assert_eq!(check_created_at(0, 1, 0, 0), Err(TimeError::TooOld));
+    }
+
+    #[test]
+    fn token_ids_split_back() {
+        for (definition_id, edition) in [(0, 0), (1, 1), (7, u32::MAX), (u64::MAX >> 32, 5)] {
+            assert_eq!(
+                split_token_id(token_id(definition_id, edition)),
+                (definition_id, edition)
+            );
+        }
+        assert_eq!(token_id(1, 1), 4_294_967_297);
+        // editions of a definition sort together, before the next definition
+        assert!(token_id(1, u32::MAX) < token_id(2, 0));
+    }
+
+    #[test]
+    fn content_hash_sorts_keys_by_encoding() {
+        // {"b": 2, "aa": 1}, the shorter key encodes first
+        let canonical = [0xa2, 0x61, b'b', 0x02, 0x62, b'a', b'a', 0x01];
+        let expected: AssetHash = Sha3_256::digest(canonical).into();
+        assert_eq!(content_hash(&Token { b: 2, aa: 1 }).unwrap(), expected);
+        assert_eq!(content_hash(&Reordered { aa: 1, b: 2 }).unwrap(), expected);
+    }
+
+    #[test]
+    fn content_hash_ignores_map_order() {
+        let nested = |keys: &[&str]| {
+            let inner: HashMap<String, u8> = keys
+                .iter()
+                .map(|k| (k.to_string(), k.len() as u8))
+                .collect();
+            vec![("outer".to_string(), inner)]
+                .into_iter()
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(
+            content_hash(&nested(&["x", "yy", "zzz"])).unwrap(),
+            content_hash(&nested(&["zzz", "x", "yy"])).unwrap()
+        );
+        assert_ne!(
+            content_hash(&nested(&["x", "yy"])).unwrap(),
+            content_hash(&nested(&["x", "yy", "zzz"])).unwrap()
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{json_to_value, DecodeError, Metadata, TokenInner, Transaction, Value};
+use candid::Principal;
+use ciborium::{from_reader, into_writer};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::Memo;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+/// Layout version written into every stored [`TokenInner`] and
+/// [`Transaction`]. Records without one were written by 0.1.0.
+pub const SCHEMA_VERSION: u16 = 1;
+
+/// Timestamps below this are seconds, 0.1.0 stored definition times that way.
+const SECONDS_CUTOFF: u64 = 1_000_000_000_000;
+
+/// Token definition as stored by 0.1.0, every holder kept a copy of the
+/// token and there were no edition numbers.
+#[derive(Deserialize)]
+struct TokenInnerV0<T> {
+    id: u64,
+    token: T,
+    supply_cap: Option<usize>,
+    created_at: u64,
+    updated_at: u64,
+    owner: Principal,
+    holders: Vec<Principal>,
+}
+
+/// Log entry as stored by 0.1.0, with principals instead of accounts and the
+/// token JSON metadata as a string.
+#[derive(Deserialize)]
+struct TransactionV0 {
+    ts: u64,
+    token_id: u64,
+    op: String,
+    from: Option<Principal>,
+    to: Option<Principal>,
+    metadata: String,
+    memo: Option<Memo>,
+}
+
+impl<E: std::fmt::Debug> From<ciborium::de::Error<E>> for DecodeError {
+    fn from(e: ciborium::de::Error<E>) -> Self {
+        DecodeError(e.to_string())
+    }
+}
+
+fn nanos(ts: u64) -> u64 {
+    if ts < SECONDS_CUTOFF {
+        ts * 1_000_000_000
+    } else {
+        ts
+    }
+}
+
+/// Encodes `record` with its `version` set to [`SCHEMA_VERSION`], records read
+/// from an older layout are written back in the current one.
+pub(crate) fn encode<R: Serialize>(record: &R, version: u16) -> Vec<u8> {
+    let mut buf = vec![];
+    if version == SCHEMA_VERSION {
+        into_writer(record, &mut buf).expect("failed to encode Collection data");
+    } else {
+        let mut value =
+            ciborium::Value::serialized(record).expect("failed to encode Collection data");
+        if let Some(map) = value.as_map_mut() {
+            map.retain(|(k, _)| k.as_text() != Some("version"));
+            map.push(("version".into(), SCHEMA_VERSION.into()));
+        }
+        into_writer(&value, &mut buf).expect("failed to encode Collection data");
+    }
+    buf
+}
+
+/// Decodes a token definition of any known layout. `version` is left at the
+/// layout it was read from, so callers can tell which records still need to be
+/// rewritten.
+pub(crate) fn decode_token<T: Hash + Metadata + DeserializeOwned>(
+    bytes: &[u8],
+) -> Result<TokenInner<T>, DecodeError> {
+    if let Ok(token) = from_reader::<TokenInner<T>, _>(bytes) {
+        if token.version > 0 {
+            return Ok(token);
+        }
+    }
+    let mut v0: TokenInnerV0<T> = from_reader(bytes)?;
+    v0.holders.sort();
+    v0.holders.dedup();
+    let editions: BTreeMap<u32, Account> = (1..)
+        .zip(v0.holders.into_iter().map(Account::from))
+        .collect();
+    Ok(TokenInner {
+        id: v0.id,
+        token: v0.token,
+        supply_cap: v0.supply_cap,
+        created_at: nanos(v0.created_at),
+        updated_at: nanos(v0.updated_at),
+        creator: v0.owner.into(),
+        minted: editions.len() as u32,
+        editions,
+        content_hash: None,
+        version: 0,
+    })
+}
+
+/// Decodes a log entry of any known layout. Entries of 0.1.0 keep the token
+/// id they were logged with, which is the definition id under editions.
+pub(crate) fn decode_transaction(bytes: &[u8]) -> Result<Transaction, DecodeError> {
+    if let Ok(tx) = from_reader::<Transaction, _>(bytes) {
+        if tx.version > 0 {
+            return Ok(tx);
+        }
+    }
+    let v0: TransactionV0 = from_reader(bytes)?;
+    let meta = match v0.op.as_str() {
+        "7mint" => serde_json::from_str(&v0.metadata)
+            .ok()
+            .and_then(json_to_value)
+            .map(|v| match v {
+                Value::Map(_) => v,
+                v => Value::Map([("icrc7:metadata".to_string(), v)].into()),
+            }),
+        _ => None,
+    };
+    Ok(Transaction {
+        ts: v0.ts,
+        token_id: Some(v0.token_id),
+        op: v0.op,
+        from: v0.from.map(Account::from),
+        to: v0.to.map(Account::from),
+        memo: v0.memo,
+        meta,
+        spender: None,
+        exp: None,
+        phash: None,
+        version: 0,
+    })
+}
+
+/// Stored record, or the raw bytes of one no known layout decodes, so a
+/// corrupt entry is skipped and quarantined instead of trapping every reader.
+#[derive(Clone)]
+pub enum Stored<R> {
+    Valid(R),
+    Corrupt { bytes: Vec<u8>, error: DecodeError },
+}
+
+impl<R> Stored<R> {
+    pub fn into_result(self) -> Result<R, DecodeError> {
+        match self {
+            Stored::Valid(r) => Ok(r),
+            Stored::Corrupt { error, .. } => Err(error),
+        }
+    }
+}
+
+impl<R> From<R> for Stored<R> {
+    fn from(r: R) -> Self {
+        Stored::Valid(r)
+    }
+}
+
+impl<T> Storable for Stored<TokenInner<T>>
+where
+    T: Hash + Metadata + Serialize + DeserializeOwned,
+{
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Stored::Valid(token) => token.to_bytes(),
+            Stored::Corrupt { bytes, .. } => Cow::Borrowed(bytes),
+        }
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        match decode_token(&bytes) {
+            Ok(token) => Stored::Valid(token),
+            Err(error) => Stored::Corrupt {
+                bytes: bytes.into_owned(),
+                error,
+            },
+        }
+    }
+}
+
+impl Storable for Stored<Transaction> {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Stored::Valid(tx) => tx.to_bytes(),
+            Stored::Corrupt { bytes, .. } => Cow::Borrowed(bytes),
+        }
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        match decode_transaction(&bytes) {
+            Ok(tx) => Stored::Valid(tx),
+            Err(error) => Stored::Corrupt {
+                bytes: bytes.into_owned(),
+                error,
+            },
+        }
+    }
+}
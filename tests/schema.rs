@@ -0,0 +1,126 @@
+//! Stored records written by earlier releases. The fixtures were encoded with
+//! the types of those releases and filled in the way their code paths fill
+//! them, 0.1.0 logged mints from the caller without a memo and transfers with
+//! the token JSON.
+
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use uncensored_greats_dao::candid::Principal;
+use uncensored_greats_dao::ic_stable_structures::Storable;
+use uncensored_greats_dao::icrc_ledger_types::icrc1::transfer::Memo;
+use uncensored_greats_dao::{Metadata, Stored, TokenInner, Transaction, Value, SCHEMA_VERSION};
+
+#[derive(Clone, Hash, Default, Serialize, Deserialize)]
+struct TestToken {
+    name: String,
+    description: Option<String>,
+}
+
+impl Metadata for TestToken {
+    fn metadata(&self) -> Vec<(String, Value)> {
+        let mut metadata = vec![("name".to_string(), Value::Text(self.name.clone()))];
+        if let Some(description) = &self.description {
+            metadata.push(("description".to_string(), Value::Text(description.clone())));
+        }
+        metadata
+    }
+}
+
+fn principal(byte: u8) -> Principal {
+    Principal::from_slice(&[byte; 29])
+}
+
+fn token(bytes: &[u8]) -> TokenInner<TestToken> {
+    TokenInner::from_bytes(Cow::Borrowed(bytes))
+}
+
+fn transaction(bytes: &[u8]) -> Transaction {
+    Transaction::from_bytes(Cow::Borrowed(bytes))
+}
+
+#[test]
+fn token_0_1_0() {
+    let t = token(include_bytes!("fixtures/token_0.1.0.cbor"));
+    assert_eq!(t.version, 0);
+    assert_eq!(t.id, 1);
+    assert_eq!(t.token.name, "test");
+    assert_eq!(t.supply_cap, Some(10));
+    assert_eq!(t.created_at, 1_700_000_000_000_000_000);
+    assert_eq!(t.updated_at, 1_700_000_100_000_000_000);
+    assert_eq!(t.creator, principal(1).into());
+    assert_eq!(t.minted, 2);
+    assert_eq!(
+        t.editions.into_iter().collect::<Vec<_>>(),
+        vec![(1, principal(2).into()), (2, principal(3).into())]
+    );
+    assert_eq!(t.content_hash, None);
+}
+
+#[test]
+fn token_rewritten_in_current_layout() {
+    let legacy = token(include_bytes!("fixtures/token_0.1.0.cbor"));
+    let t = token(&legacy.to_bytes());
+    assert_eq!(t.version, SCHEMA_VERSION);
+    assert_eq!(t.created_at, legacy.created_at);
+    assert_eq!(t.creator, legacy.creator);
+    assert_eq!(t.minted, legacy.minted);
+    assert_eq!(t.editions, legacy.editions);
+    let again = token(&t.to_bytes());
+    assert_eq!(again.version, SCHEMA_VERSION);
+    assert_eq!(again.editions, legacy.editions);
+}
+
+#[test]
+fn transactions_0_1_0() {
+    let mint = transaction(include_bytes!("fixtures/mint_0.1.0.cbor"));
+    assert_eq!(mint.version, 0);
+    assert_eq!(mint.op, "7mint");
+    assert_eq!(mint.token_id, Some(1));
+    assert_eq!(mint.ts, 1_700_000_200_000_000_000);
+    assert_eq!(mint.from, Some(principal(1).into()));
+    assert_eq!(mint.to, Some(principal(2).into()));
+    assert_eq!(mint.memo, None);
+    assert_eq!(
+        mint.meta,
+        Some(Value::Map(
+            [
+                ("name".to_string(), Value::Text("test".to_string())),
+                (
+                    "description".to_string(),
+                    Value::Text("fixture".to_string())
+                ),
+            ]
+            .into()
+        ))
+    );
+
+    let xfr = transaction(include_bytes!("fixtures/xfr_0.1.0.cbor"));
+    assert_eq!(xfr.op, "7xfr");
+    assert_eq!(xfr.from, Some(principal(2).into()));
+    assert_eq!(xfr.to, Some(principal(3).into()));
+    assert_eq!(xfr.memo, Some(Memo::from(vec![1, 2, 3])));
+    // only mints keep the token metadata
+    assert_eq!(xfr.meta, None);
+
+    let rewritten = transaction(&xfr.to_bytes());
+    assert_eq!(rewritten.version, SCHEMA_VERSION);
+    assert_eq!(rewritten.to, xfr.to);
+}
+
+#[test]
+fn corrupt_records_keep_their_bytes() {
+    let bytes = &include_bytes!("fixtures/token_0.1.0.cbor")[..20];
+    let stored = Stored::<TokenInner<TestToken>>::from_bytes(Cow::Borrowed(bytes));
+    assert!(matches!(stored, Stored::Corrupt { .. }));
+    assert_eq!(stored.to_bytes(), bytes);
+    assert!(stored.into_result().is_err());
+
+    let bytes = b"not cbor";
+    let stored = Stored::<Transaction>::from_bytes(Cow::Borrowed(bytes));
+    assert_eq!(stored.to_bytes(), &bytes[..]);
+    assert!(stored.into_result().is_err());
+
+    let valid = include_bytes!("fixtures/xfr_0.1.0.cbor");
+    let stored = Stored::<Transaction>::from_bytes(Cow::Borrowed(valid));
+    assert_eq!(stored.into_result().unwrap().op, "7xfr");
+}